@@ -1,40 +1,197 @@
-use bevy::{audio::AudioSink, prelude::*};
+use bevy::{audio::play_queued_audio_system, prelude::*};
 
-struct MusicController(Handle<AudioSink>);
+use crate::{
+    config::{load_ron, save_ron},
+    controls::Action,
+    map::{LevelId, LevelTransition, MapQuery},
+};
+
+pub use lengths::TrackLengths;
+pub use music::{MusicDirector, MusicSource, Playlist, PlaylistMode};
+pub use settings::{AudioBus, AudioConfig, AudioSettings, Bus};
+pub use sfx::{MapSfx, PlaySfx, SfxInfo, SfxManifest, SfxPool, SfxRegistry};
+pub use spatial::{AmbientEmitter, SpatialGains, SpatialSettings, SpatialSource};
+pub use state::{MusicEvent, MusicState};
+
+use sfx::SfxManifestLoader;
+use spatial::AmbientVoices;
+
+mod lengths;
+mod music;
+mod settings;
+mod sfx;
+mod spatial;
+mod state;
+
+// change of a bus volume per key press
+const VOLUME_STEP: f32 = 0.1;
 
 pub struct AudioPlugin;
 
 impl Plugin for AudioPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_audio).add_system(pause_audio);
+        app.init_resource::<AudioConfig>()
+            .init_resource::<AudioSettings>()
+            .init_resource::<MusicDirector>()
+            .init_resource::<MusicState>()
+            .add_event::<MusicEvent>()
+            .add_asset::<MusicSource>()
+            .init_non_send_resource::<AudioOutput<MusicSource>>()
+            .init_resource::<Audio<MusicSource>>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                play_queued_audio_system::<MusicSource>.exclusive_system(),
+            )
+            .init_resource::<TrackLengths>()
+            .add_asset::<SfxManifest>()
+            .init_asset_loader::<SfxManifestLoader>()
+            .init_resource::<SfxRegistry>()
+            .init_resource::<SfxPool>()
+            .add_event::<PlaySfx>()
+            .add_asset::<SpatialSource>()
+            .init_non_send_resource::<AudioOutput<SpatialSource>>()
+            .init_resource::<Audio<SpatialSource>>()
+            .init_resource::<SpatialSettings>()
+            .init_resource::<AmbientVoices>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                play_queued_audio_system::<SpatialSource>.exclusive_system(),
+            )
+            .add_startup_system_to_stage(StartupStage::PreStartup, load_audio_settings)
+            .add_startup_system(sfx::setup_sfx)
+            .add_system(sfx::build_sfx_registry)
+            .add_system(level_sfx.before(AudioSystem::Sfx))
+            .add_system(footstep_sfx.before(AudioSystem::Sfx))
+            .add_system(sfx::play_sfx.label(AudioSystem::Sfx))
+            .add_system(sfx::update_sfx.after(AudioSystem::Sfx))
+            .add_system(spatial::play_ambient.label(AudioSystem::Sfx))
+            .add_system(spatial::spatialize.after(AudioSystem::Sfx))
+            .add_system(lengths::measure_tracks.after(AudioSystem::Music))
+            .add_system(adjust_volume.before(AudioSystem::Music))
+            .add_system(save_audio_settings.after(AudioSystem::Music))
+            .add_system(play_level_music.before(AudioSystem::Music))
+            .add_system(pause_audio.before(AudioSystem::State))
+            .add_system(state::update_music_state.label(AudioSystem::State))
+            .add_system(
+                state::apply_music_state
+                    .after(AudioSystem::State)
+                    .before(AudioSystem::Music),
+            )
+            .add_system(music::update_music.label(AudioSystem::Music));
+    }
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum AudioSystem {
+    State,
+    Music,
+    Sfx,
+}
+
+// entities making a sound when they step onto a stair
+#[derive(Component, Default)]
+pub struct Footsteps {
+    stair: Option<(LevelId, usize)>,
+}
+
+fn load_audio_settings(config: Res<AudioConfig>, mut settings: ResMut<AudioSettings>) {
+    match load_ron(&config.path) {
+        Ok(loaded) => *settings = loaded,
+        Err(error) => {
+            if config.path.exists() {
+                warn!("failed to read {}: {}", config.path.display(), error);
+            }
+        }
+    }
+}
+
+// write the settings whenever they change, so they survive a restart
+fn save_audio_settings(config: Res<AudioConfig>, settings: Res<AudioSettings>) {
+    if settings.is_changed() && !settings.is_added() {
+        if let Err(error) = save_ron(&config.path, &*settings) {
+            warn!("failed to save {}: {}", config.path.display(), error);
+        }
+    }
+}
+
+// turn the music down or up without pausing it, or mute everything
+fn adjust_volume(actions: Res<Input<Action>>, mut settings: ResMut<AudioSettings>) {
+    if actions.just_pressed(Action::MusicVolumeDown) {
+        settings.music.nudge(-VOLUME_STEP);
+    }
+
+    if actions.just_pressed(Action::MusicVolumeUp) {
+        settings.music.nudge(VOLUME_STEP);
+    }
+
+    if actions.just_pressed(Action::MuteAudio) {
+        settings.master.muted = !settings.master.muted;
     }
 }
 
-// BGM
-fn setup_audio(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    audio: Res<Audio>,
-    audio_sinks: Res<Assets<AudioSink>>,
+// the enter sound of every level that became active
+fn level_sfx(
+    transition: Res<LevelTransition>,
+    maps: MapQuery,
+    mut active: Local<Vec<LevelId>>,
+    mut sfx: EventWriter<PlaySfx>,
 ) {
-    let music = asset_server.load("sounds/Lady_Maria.ogg");
-    // play audio and upgrade to a strong handle
-    let handle = audio_sinks.get_handle(audio.play(music));
-    commands.insert_resource(MusicController(handle));
+    if !transition.is_changed() {
+        return;
+    }
+    for level in transition.active() {
+        if active.contains(level) {
+            continue;
+        }
+        if let Some(enter) = maps.sfx(level).and_then(|sfx| sfx.enter.as_ref()) {
+            sfx.send(PlaySfx::new(enter.as_str()));
+        }
+    }
+    *active = transition.active().to_vec();
 }
 
-fn pause_audio(
-    keyboard_input: Res<Input<KeyCode>>,
-    audio_sinks: Res<Assets<AudioSink>>,
-    music_controller: Res<MusicController>,
+fn footstep_sfx(
+    maps: MapQuery,
+    mut walkers: Query<(&GlobalTransform, &mut Footsteps)>,
+    mut sfx: EventWriter<PlaySfx>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::Space) {
-        if let Some(sink) = audio_sinks.get(&music_controller.0) {
-            if sink.is_paused() {
-                sink.play()
-            } else {
-                sink.pause()
+    for (transform, mut footsteps) in walkers.iter_mut() {
+        let position = transform.translation;
+        let stair = maps
+            .stair_at(position)
+            .map(|(level, index)| (level.clone(), index));
+        if stair == footsteps.stair {
+            continue;
+        }
+        if let Some((level, _)) = &stair {
+            if let Some(stairs) = maps.sfx(level).and_then(|sfx| sfx.stairs.as_ref()) {
+                sfx.send(PlaySfx::new(stairs.as_str()).at(position));
             }
         }
+        footsteps.stair = stair;
+    }
+}
+
+// BGM of the level entered last, or the default playlist
+fn play_level_music(
+    transition: Res<LevelTransition>,
+    maps: MapQuery,
+    mut director: ResMut<MusicDirector>,
+) {
+    if !transition.is_changed() {
+        return;
+    }
+    let playlist = transition
+        .active()
+        .last()
+        .and_then(|level| maps.music(level))
+        .cloned()
+        .unwrap_or_else(|| director.default_playlist.clone());
+    director.play(playlist);
+}
+
+fn pause_audio(actions: Res<Input<Action>>, mut events: EventWriter<MusicEvent>) {
+    if actions.just_pressed(Action::ToggleMusic) {
+        events.send(MusicEvent::TogglePause);
     }
 }
@@ -0,0 +1,67 @@
+use bevy::{
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use futures_lite::future;
+use rodio::Source;
+use std::collections::{HashMap, HashSet};
+
+// lengths in seconds of the sounds played so far, measured in the background
+#[derive(Default)]
+pub struct TrackLengths {
+    lengths: HashMap<Handle<AudioSource>, f32>,
+    requested: HashSet<Handle<AudioSource>>,
+    measuring: HashMap<Handle<AudioSource>, Task<f32>>,
+}
+
+impl TrackLengths {
+    // the length of a sound, measuring it on the first call
+    pub fn get(&mut self, source: &Handle<AudioSource>) -> Option<f32> {
+        let length = self.lengths.get(source).copied();
+        if length.is_none() && !self.measuring.contains_key(source) {
+            self.requested.insert(source.clone());
+        }
+        length
+    }
+}
+
+// length of a sound, decoding it when the format does not tell
+fn track_length(source: &AudioSource) -> f32 {
+    let decoder = source.decoder();
+    if let Some(duration) = decoder.total_duration() {
+        return duration.as_secs_f32();
+    }
+    let samples_per_second = decoder.sample_rate() as f32 * decoder.channels() as f32;
+    decoder.count() as f32 / samples_per_second
+}
+
+pub(super) fn measure_tracks(
+    mut lengths: ResMut<TrackLengths>,
+    sources: Res<Assets<AudioSource>>,
+    task_pool: Res<AsyncComputeTaskPool>,
+) {
+    let lengths = &mut *lengths;
+    // sources still loading stay requested
+    let measuring = &mut lengths.measuring;
+    lengths
+        .requested
+        .retain(|handle| match sources.get(handle) {
+            Some(source) => {
+                let source = source.clone();
+                let task = task_pool.spawn(async move { track_length(&source) });
+                measuring.insert(handle.clone(), task);
+                false
+            }
+            None => true,
+        });
+    let done = &mut lengths.lengths;
+    lengths.measuring.retain(
+        |handle, task| match future::block_on(future::poll_once(task)) {
+            Some(length) => {
+                done.insert(handle.clone(), length);
+                false
+            }
+            None => true,
+        },
+    );
+}
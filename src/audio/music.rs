@@ -0,0 +1,343 @@
+use bevy::{asset::LoadState, audio::AudioSink, prelude::*, reflect::TypeUuid};
+use rodio::{source::SkipDuration, Source};
+use serde::Deserialize;
+use std::{collections::HashMap, io::Cursor, time::Duration};
+
+use super::{AudioBus, AudioSettings, TrackLengths};
+
+// the order the tracks of a playlist are played in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum PlaylistMode {
+    // every track in order, then from the start again
+    #[default]
+    Loop,
+    // every track in a random order, reshuffled after each pass
+    Shuffle,
+    // every track in order once, then silence
+    Sequence,
+}
+
+// music of a level, declared as `music` in its map script
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Playlist {
+    // asset paths of the tracks
+    pub tracks: Vec<String>,
+    #[serde(default)]
+    pub mode: PlaylistMode,
+}
+
+impl Playlist {
+    pub fn new(tracks: impl IntoIterator<Item = impl Into<String>>, mode: PlaylistMode) -> Self {
+        Self {
+            tracks: tracks.into_iter().map(Into::into).collect(),
+            mode,
+        }
+    }
+}
+
+// a track started part way through, one asset per playing track
+#[derive(TypeUuid)]
+#[uuid = "0d6c3b9e-8f57-4a1e-b2c4-7e9a51f3c826"]
+pub struct MusicSource {
+    pub source: AudioSource,
+    // seconds skipped at the start
+    pub start: f32,
+}
+
+impl Decodable for MusicSource {
+    type Decoder = SkipDuration<rodio::Decoder<Cursor<AudioSource>>>;
+    type DecoderItem = i16;
+
+    fn decoder(&self) -> Self::Decoder {
+        rodio::Decoder::new(Cursor::new(self.source.clone()))
+            .unwrap()
+            .skip_duration(Duration::from_secs_f32(self.start.max(0.0)))
+    }
+}
+
+// a track fading in, playing or fading out
+struct Voice {
+    track: String,
+    source: Handle<AudioSource>,
+    sink: Handle<AudioSink>,
+    // seconds into the track
+    elapsed: f32,
+    // 0 is silent, 1 full volume
+    fade: f32,
+    fading_out: bool,
+}
+
+// plays playlists, crossfading between their tracks and from one playlist to
+// the next
+pub struct MusicDirector {
+    // seconds a crossfade takes
+    pub crossfade: f32,
+    // played when the active level has no music of its own
+    pub default_playlist: Playlist,
+    playlist: Playlist,
+    order: Vec<usize>,
+    position: usize,
+    // start the track at `position` on the next update
+    pending: bool,
+    // track waiting for its source to load
+    starting: Option<(String, Handle<AudioSource>)>,
+    paused: bool,
+    // no track is started while silenced
+    silenced: bool,
+    // volume the music is ducked to and the current ramp towards it
+    duck: f32,
+    duck_gain: f32,
+    voices: Vec<Voice>,
+    // where tracks cut off before their end pick up again
+    resume: HashMap<String, f32>,
+    seed: u64,
+}
+
+impl Default for MusicDirector {
+    fn default() -> Self {
+        Self {
+            crossfade: 2.0,
+            default_playlist: Playlist::new(["sounds/Lady_Maria.ogg"], PlaylistMode::Loop),
+            playlist: Playlist::default(),
+            order: Vec::new(),
+            position: 0,
+            pending: false,
+            starting: None,
+            paused: false,
+            silenced: false,
+            duck: 1.0,
+            duck_gain: 1.0,
+            voices: Vec::new(),
+            resume: HashMap::new(),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl MusicDirector {
+    // crossfade into a playlist, unless it is already playing
+    pub fn play(&mut self, playlist: Playlist) {
+        if playlist == self.playlist && !self.order.is_empty() {
+            return;
+        }
+        self.playlist = playlist;
+        self.position = 0;
+        self.shuffle();
+        self.fade_out_all(true);
+        self.pending = !self.order.is_empty();
+    }
+
+    // crossfade into the next track of the playlist
+    pub fn next(&mut self) {
+        self.fade_out_all(false);
+        self.pending = self.advance();
+    }
+
+    // fade out and forget the playlist
+    pub fn stop(&mut self) {
+        self.playlist = Playlist::default();
+        self.order.clear();
+        self.pending = false;
+        self.fade_out_all(true);
+    }
+
+    pub fn playlist(&self) -> &Playlist {
+        &self.playlist
+    }
+
+    // asset path of the track playing at full volume or fading in
+    pub fn current_track(&self) -> Option<&str> {
+        self.voices
+            .iter()
+            .find(|voice| !voice.fading_out)
+            .map(|voice| voice.track.as_str())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // pause every sink, they keep their position
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_silenced(&self) -> bool {
+        self.silenced
+    }
+
+    // fade out the playlist, keeping it and the position of its track for
+    // when the music is no longer silenced
+    pub fn set_silenced(&mut self, silenced: bool) {
+        if silenced == self.silenced {
+            return;
+        }
+        self.silenced = silenced;
+        if silenced {
+            self.fade_out_all(true);
+        }
+        self.pending = !silenced && !self.order.is_empty();
+    }
+
+    // ramp the music volume towards `volume`, 1 to stop ducking
+    pub fn set_duck(&mut self, volume: f32) {
+        self.duck = volume.clamp(0.0, 1.0);
+    }
+
+    // the order of the next pass over the playlist
+    fn shuffle(&mut self) {
+        let count = self.playlist.tracks.len();
+        let last = self.order.last().copied();
+        self.order = (0..count).collect();
+        if self.playlist.mode != PlaylistMode::Shuffle {
+            return;
+        }
+        // Fisher-Yates with xorshift, good enough for music
+        for i in (1..count).rev() {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            self.order.swap(i, (self.seed % (i as u64 + 1)) as usize);
+        }
+        // do not play the last track of the previous pass twice in a row
+        if count > 1 && self.order.first().copied() == last {
+            self.order.swap(0, count - 1);
+        }
+    }
+
+    // move to the next track, false at the end of a sequence
+    fn advance(&mut self) -> bool {
+        if self.order.is_empty() {
+            return false;
+        }
+        self.position += 1;
+        if self.position < self.order.len() {
+            return true;
+        }
+        if self.playlist.mode == PlaylistMode::Sequence {
+            self.order.clear();
+            return false;
+        }
+        self.position = 0;
+        self.shuffle();
+        true
+    }
+
+    // fade out every voice, remembering where the playing one stopped
+    fn fade_out_all(&mut self, remember: bool) {
+        self.starting = None;
+        for voice in &mut self.voices {
+            if remember && !voice.fading_out {
+                self.resume.insert(voice.track.clone(), voice.elapsed);
+            }
+            voice.fading_out = true;
+        }
+    }
+}
+
+// start pending tracks, ramp the volumes and move on before a track ends
+#[allow(clippy::too_many_arguments)]
+pub(super) fn update_music(
+    mut director: ResMut<MusicDirector>,
+    settings: Res<AudioSettings>,
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    sources: Res<Assets<AudioSource>>,
+    mut music_sources: ResMut<Assets<MusicSource>>,
+    audio: Res<Audio<MusicSource>>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    mut lengths: ResMut<TrackLengths>,
+) {
+    let director = &mut *director;
+    if director.pending && !director.silenced {
+        director.pending = false;
+        let track = director.playlist.tracks[director.order[director.position]].clone();
+        let source = asset_server.load(track.as_str());
+        director.starting = Some((track, source));
+    }
+
+    // the source is needed to skip to where the track was left
+    if let Some((track, source)) = director.starting.take() {
+        match sources.get(&source) {
+            Some(loaded) => {
+                let start = director.resume.remove(&track).unwrap_or(0.0);
+                let music = music_sources.add(MusicSource {
+                    source: loaded.clone(),
+                    start,
+                });
+                let playback = PlaybackSettings {
+                    volume: 0.0,
+                    ..PlaybackSettings::ONCE
+                };
+                let sink = audio.play_with_settings(music, playback);
+                director.voices.push(Voice {
+                    track,
+                    source,
+                    sink: audio_sinks.get_handle(sink),
+                    elapsed: start,
+                    fade: 0.0,
+                    fading_out: false,
+                });
+            }
+            None if asset_server.get_load_state(&source) == LoadState::Failed => {
+                warn!("failed to load the track {}", track);
+                director.next();
+            }
+            None => director.starting = Some((track, source)),
+        }
+    }
+
+    let delta = time.delta_seconds();
+    let step = if director.crossfade > 0.0 {
+        delta / director.crossfade
+    } else {
+        1.0
+    };
+    if !director.paused {
+        // ducking takes a quarter of a crossfade
+        let duck_step = step * 4.0;
+        director.duck_gain += (director.duck - director.duck_gain).clamp(-duck_step, duck_step);
+    }
+    let volume = settings.volume(AudioBus::Music) * director.duck_gain;
+    let mut track_ending = false;
+    for voice in &mut director.voices {
+        let sink = match audio_sinks.get(&voice.sink) {
+            Some(sink) => sink,
+            // a track that never started is dropped once it is replaced
+            None if voice.fading_out => {
+                voice.fade = 0.0;
+                continue;
+            }
+            // not started yet
+            None => continue,
+        };
+        if director.paused {
+            sink.pause();
+            continue;
+        }
+        sink.play();
+        voice.elapsed += delta;
+        voice.fade = if voice.fading_out {
+            (voice.fade - step).max(0.0)
+        } else {
+            (voice.fade + step).min(1.0)
+        };
+        sink.set_volume(volume * voice.fade);
+        if voice.fading_out && voice.fade <= 0.0 {
+            sink.stop();
+        }
+        let ends_in = lengths
+            .get(&voice.source)
+            .map(|length| length - voice.elapsed);
+        if !voice.fading_out && ends_in.map_or(false, |ends_in| ends_in <= director.crossfade) {
+            track_ending = true;
+        }
+    }
+    director
+        .voices
+        .retain(|voice| !(voice.fading_out && voice.fade <= 0.0));
+    if track_ending {
+        director.next();
+    }
+}
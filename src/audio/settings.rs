@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+// volume of a group of sounds
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Bus {
+    // 0 is silent, 1 the volume of the file
+    pub volume: f32,
+    pub muted: bool,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl Bus {
+    pub fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume.clamp(0.0, 1.0)
+        }
+    }
+
+    // change the volume by `step`, unmuting the bus
+    pub fn nudge(&mut self, step: f32) {
+        self.volume = (self.volume + step).clamp(0.0, 1.0);
+        self.muted = false;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AudioBus {
+    Master,
+    Music,
+    Sfx,
+}
+
+// volumes of every sink, saved to `AudioConfig::path`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master: Bus,
+    pub music: Bus,
+    pub sfx: Bus,
+    // share of the music volume left while ducked
+    pub duck_volume: f32,
+    pub pause_on_focus_loss: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        Self {
+            master: Bus::default(),
+            music: Bus::default(),
+            sfx: Bus::default(),
+            duck_volume: 0.3,
+            pause_on_focus_loss: true,
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn bus(&self, bus: AudioBus) -> &Bus {
+        match bus {
+            AudioBus::Master => &self.master,
+            AudioBus::Music => &self.music,
+            AudioBus::Sfx => &self.sfx,
+        }
+    }
+
+    pub fn bus_mut(&mut self, bus: AudioBus) -> &mut Bus {
+        match bus {
+            AudioBus::Master => &mut self.master,
+            AudioBus::Music => &mut self.music,
+            AudioBus::Sfx => &mut self.sfx,
+        }
+    }
+
+    // volume of a sink on `bus`, the master bus included
+    pub fn volume(&self, bus: AudioBus) -> f32 {
+        match bus {
+            AudioBus::Master => self.master.gain(),
+            _ => self.master.gain() * self.bus(bus).gain(),
+        }
+    }
+}
+
+// where the audio settings are read from and saved to
+pub struct AudioConfig {
+    pub path: PathBuf,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("config/audio.ron"),
+        }
+    }
+}
@@ -0,0 +1,338 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadState, LoadedAsset},
+    audio::AudioSink,
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use super::{AudioBus, AudioSettings, SpatialGains, SpatialSource, TrackLengths};
+
+// play a sound of the `SfxRegistry`
+#[derive(Debug, Clone)]
+pub struct PlaySfx {
+    pub id: String,
+    // where the sound comes from, `None` for interface sounds
+    pub position: Option<Vec3>,
+    // multiplied with the volume of the sound and the SFX bus
+    pub volume: f32,
+}
+
+impl PlaySfx {
+    pub fn new(id: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            position: None,
+            volume: 1.0,
+        }
+    }
+
+    pub fn at(mut self, position: Vec3) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    pub fn with_volume(mut self, volume: f32) -> Self {
+        self.volume = volume;
+        self
+    }
+}
+
+// a sound entry of `sounds.sfx.ron`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SfxInfo {
+    // asset path of the sound
+    pub path: String,
+    #[serde(default = "SfxInfo::default_volume")]
+    pub volume: f32,
+    // the oldest instance is stopped when another one would exceed this
+    #[serde(default = "SfxInfo::default_max_instances")]
+    pub max_instances: usize,
+    // seconds before the sound can be played again
+    #[serde(default)]
+    pub cooldown: f32,
+}
+
+impl SfxInfo {
+    fn default_volume() -> f32 {
+        1.0
+    }
+
+    fn default_max_instances() -> usize {
+        4
+    }
+}
+
+// contents of `sounds.sfx.ron`
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "4f0c7e0a-38a8-4b52-9d57-0b7c46b4e3a1"]
+#[serde(deny_unknown_fields)]
+pub struct SfxManifest {
+    pub sounds: HashMap<String, SfxInfo>,
+}
+
+#[derive(Default)]
+pub(super) struct SfxManifestLoader;
+
+impl AssetLoader for SfxManifestLoader {
+    fn extensions(&self) -> &[&str] {
+        &["sfx.ron"]
+    }
+
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let manifest = ron::de::from_bytes::<SfxManifest>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(manifest));
+            Ok(())
+        })
+    }
+}
+
+// sound effects known by name, loaded up front
+#[derive(Default)]
+pub struct SfxRegistry {
+    sounds: HashMap<String, (SfxInfo, Handle<AudioSource>)>,
+}
+
+impl SfxRegistry {
+    pub fn get(&self, id: &str) -> Option<&SfxInfo> {
+        self.sounds.get(id).map(|(info, _)| info)
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.sounds.contains_key(id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.sounds.keys().map(String::as_str)
+    }
+
+    pub(super) fn source(&self, id: &str) -> Option<&Handle<AudioSource>> {
+        self.sounds.get(id).map(|(_, source)| source)
+    }
+}
+
+// sound effects of a map, declared as `sfx` in its map script, silent unless
+// given
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MapSfx {
+    // played when the level is entered
+    pub enter: Option<String>,
+    // played when a `Footsteps` entity steps onto a stair
+    pub stairs: Option<String>,
+}
+
+// a playing sound effect
+pub(super) struct SfxInstance {
+    pub(super) id: String,
+    pub(super) source: Handle<AudioSource>,
+    pub(super) sink: Handle<AudioSink>,
+    pub(super) position: Option<Vec3>,
+    // panning of sounds with a position
+    pub(super) gains: Option<Arc<SpatialGains>>,
+    // event volume times the volume of the sound
+    pub(super) volume: f32,
+    pub(super) started: f64,
+}
+
+// sound effects playing and when each one was played last
+#[derive(Default)]
+pub struct SfxPool {
+    pub(super) instances: Vec<SfxInstance>,
+    last_played: HashMap<String, f64>,
+    // placed sounds waiting for their source to load
+    deferred: Vec<PlaySfx>,
+}
+
+impl SfxPool {
+    // instances of a sound currently playing
+    pub fn playing(&self, id: &str) -> usize {
+        self.instances
+            .iter()
+            .filter(|instance| instance.id == id)
+            .count()
+    }
+}
+
+pub(super) struct SfxManifestHandle(Handle<SfxManifest>);
+
+pub(super) fn setup_sfx(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let handle = asset_server.load("sounds/sounds.sfx.ron");
+    commands.insert_resource(SfxManifestHandle(handle));
+}
+
+// rebuild the registry whenever `sounds.sfx.ron` changes
+pub(super) fn build_sfx_registry(
+    mut registry: ResMut<SfxRegistry>,
+    mut events: EventReader<AssetEvent<SfxManifest>>,
+    asset_server: Res<AssetServer>,
+    manifest_handle: Res<SfxManifestHandle>,
+    manifests: Res<Assets<SfxManifest>>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle }
+                if *handle == manifest_handle.0 =>
+            {
+                if let Some(manifest) = manifests.get(handle) {
+                    registry.sounds = manifest
+                        .sounds
+                        .iter()
+                        .map(|(id, info)| {
+                            let source = asset_server.load(info.path.as_str());
+                            (id.clone(), (info.clone(), source))
+                        })
+                        .collect();
+                    info!("registered {} sound effects", registry.sounds.len());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// start the requested sounds within their cooldowns and instance limits
+#[allow(clippy::too_many_arguments)]
+pub(super) fn play_sfx(
+    mut requests: EventReader<PlaySfx>,
+    mut pool: ResMut<SfxPool>,
+    mut unknown: Local<HashSet<String>>,
+    mut failed: Local<HashSet<String>>,
+    registry: Res<SfxRegistry>,
+    settings: Res<AudioSettings>,
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    sources: Res<Assets<AudioSource>>,
+    mut spatial_sources: ResMut<Assets<SpatialSource>>,
+    audio: Res<Audio>,
+    spatial_audio: Res<Audio<SpatialSource>>,
+    audio_sinks: Res<Assets<AudioSink>>,
+) {
+    let now = time.seconds_since_startup();
+    let deferred = std::mem::take(&mut pool.deferred);
+    for request in deferred.iter().chain(requests.iter()) {
+        let (info, source) = match registry.sounds.get(&request.id) {
+            Some(sound) => sound,
+            None => {
+                if unknown.insert(request.id.clone()) {
+                    warn!("unknown sound effect {}", request.id);
+                }
+                continue;
+            }
+        };
+        if let Some(last) = pool.last_played.get(&request.id) {
+            if now - last < info.cooldown as f64 {
+                continue;
+            }
+        }
+        if info.max_instances == 0 {
+            continue;
+        }
+        // `Audio` would keep a source that never loads queued forever
+        if asset_server.get_load_state(source) == LoadState::Failed {
+            if failed.insert(request.id.clone()) {
+                warn!(
+                    "sound effect {} failed to load from {}",
+                    request.id, info.path
+                );
+            }
+            continue;
+        }
+        // steal the oldest instances over the limit
+        while pool.playing(&request.id) >= info.max_instances {
+            let oldest = pool
+                .instances
+                .iter()
+                .position(|instance| instance.id == request.id)
+                .unwrap();
+            let instance = pool.instances.remove(oldest);
+            if let Some(sink) = audio_sinks.get(&instance.sink) {
+                sink.stop();
+            }
+        }
+
+        // a placed sound is panned by its own decoder, which needs the data
+        let spatial = match (request.position, sources.get(source)) {
+            (None, _) => None,
+            (Some(_), Some(loaded)) => Some(loaded.clone()),
+            (Some(_), None) => {
+                pool.deferred.push(request.clone());
+                continue;
+            }
+        };
+
+        let volume = request.volume * info.volume;
+        let playback = PlaybackSettings {
+            volume: volume * settings.volume(AudioBus::Sfx),
+            ..PlaybackSettings::ONCE
+        };
+        let (sink, gains) = match spatial {
+            Some(loaded) => {
+                let gains = Arc::new(SpatialGains::default());
+                let spatial = spatial_sources.add(SpatialSource {
+                    source: loaded,
+                    gains: gains.clone(),
+                });
+                (
+                    spatial_audio.play_with_settings(spatial, playback),
+                    Some(gains),
+                )
+            }
+            None => (audio.play_with_settings(source.clone(), playback), None),
+        };
+        pool.instances.push(SfxInstance {
+            id: request.id.clone(),
+            source: source.clone(),
+            sink: audio_sinks.get_handle(sink),
+            position: request.position,
+            gains,
+            volume,
+            started: now,
+        });
+        pool.last_played.insert(request.id.clone(), now);
+    }
+}
+
+// seconds a sound whose length is still unknown counts as playing
+const UNMEASURED_TIMEOUT: f64 = 30.0;
+
+// forget finished sounds and follow the SFX bus
+pub(super) fn update_sfx(
+    mut pool: ResMut<SfxPool>,
+    mut lengths: ResMut<TrackLengths>,
+    settings: Res<AudioSettings>,
+    time: Res<Time>,
+    asset_server: Res<AssetServer>,
+    audio_sinks: Res<Assets<AudioSink>>,
+) {
+    let now = time.seconds_since_startup();
+    pool.instances.retain(|instance| {
+        let elapsed = now - instance.started;
+        let playing = match lengths.get(&instance.source) {
+            Some(length) => elapsed < length as f64,
+            None => {
+                asset_server.get_load_state(&instance.source) != LoadState::Failed
+                    && elapsed < UNMEASURED_TIMEOUT
+            }
+        };
+        if !playing {
+            return false;
+        }
+        if settings.is_changed() {
+            if let Some(sink) = audio_sinks.get(&instance.sink) {
+                sink.set_volume(instance.volume * settings.volume(AudioBus::Sfx));
+            }
+        }
+        true
+    });
+}
@@ -0,0 +1,260 @@
+use bevy::{audio::AudioSink, prelude::*, reflect::TypeUuid};
+use rodio::Source;
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use super::{AudioBus, AudioSettings, SfxPool, SfxRegistry};
+use crate::camera::{CameraRig, MovableCamera};
+
+// left and right gains of a playing sound, written by the game and read by
+// the audio thread
+#[derive(Debug)]
+pub struct SpatialGains {
+    left: AtomicU32,
+    right: AtomicU32,
+}
+
+impl Default for SpatialGains {
+    fn default() -> Self {
+        Self {
+            left: AtomicU32::new(1.0f32.to_bits()),
+            right: AtomicU32::new(1.0f32.to_bits()),
+        }
+    }
+}
+
+impl SpatialGains {
+    pub fn set(&self, left: f32, right: f32) {
+        self.left.store(left.to_bits(), Ordering::Relaxed);
+        self.right.store(right.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> (f32, f32) {
+        (
+            f32::from_bits(self.left.load(Ordering::Relaxed)),
+            f32::from_bits(self.right.load(Ordering::Relaxed)),
+        )
+    }
+}
+
+// a sound played in stereo with gains that change while it plays, one asset
+// per playing instance
+#[derive(TypeUuid)]
+#[uuid = "b1f4a7e2-6c0d-4d8e-9a43-2f5e8c71d0b6"]
+pub struct SpatialSource {
+    pub source: AudioSource,
+    pub gains: Arc<SpatialGains>,
+}
+
+impl Decodable for SpatialSource {
+    type Decoder = SpatialDecoder;
+    type DecoderItem = f32;
+
+    fn decoder(&self) -> Self::Decoder {
+        SpatialDecoder {
+            inner: rodio::Decoder::new(Cursor::new(self.source.clone())).unwrap(),
+            gains: self.gains.clone(),
+            right: None,
+        }
+    }
+}
+
+// mixes every frame of the inner decoder down to mono and pans it
+pub struct SpatialDecoder {
+    inner: rodio::Decoder<Cursor<AudioSource>>,
+    gains: Arc<SpatialGains>,
+    // right sample of the current frame
+    right: Option<f32>,
+}
+
+impl Iterator for SpatialDecoder {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let channels = self.inner.channels().max(1);
+        let mut sum = 0.0;
+        for channel in 0..channels {
+            match self.inner.next() {
+                Some(sample) => sum += sample as f32 / i16::MAX as f32,
+                None if channel == 0 => return None,
+                None => break,
+            }
+        }
+        let mono = sum / channels as f32;
+        let (left, right) = self.gains.get();
+        self.right = Some(mono * right);
+        Some(mono * left)
+    }
+}
+
+impl Source for SpatialDecoder {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.inner.total_duration()
+    }
+}
+
+pub struct SpatialSettings {
+    // full volume up to this distance from the camera focus
+    pub reference_distance: f32,
+    // silent from this distance on
+    pub max_distance: f32,
+    pub rolloff: f32,
+    // offset along screen-right at which a sound is fully on one side
+    pub pan_width: f32,
+}
+
+impl Default for SpatialSettings {
+    fn default() -> Self {
+        Self {
+            reference_distance: 4.0,
+            max_distance: 40.0,
+            rolloff: 1.0,
+            pan_width: 12.0,
+        }
+    }
+}
+
+impl SpatialSettings {
+    // left and right gains of a sound at `position`
+    pub fn gains(&self, rig: &CameraRig, position: Vec3) -> (f32, f32) {
+        let offset = position - rig.focus;
+        let distance = offset.length();
+        if distance >= self.max_distance {
+            return (0.0, 0.0);
+        }
+        let reference = self.reference_distance.max(0.01);
+        let attenuation = reference / (reference + self.rolloff * (distance - reference).max(0.0));
+        // equal power panning
+        let pan = (offset.dot(rig.right()) / self.pan_width.max(0.01)).clamp(-1.0, 1.0);
+        let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        (attenuation * angle.cos(), attenuation * angle.sin())
+    }
+}
+
+// a looping sound of the `SfxRegistry` placed on the map, declared as
+// `ambient` in the map script
+#[derive(Component, Debug, Clone)]
+pub struct AmbientEmitter {
+    pub sound: String,
+    pub volume: f32,
+}
+
+struct AmbientVoice {
+    sink: Handle<AudioSink>,
+    gains: Arc<SpatialGains>,
+    volume: f32,
+}
+
+// sinks of the ambient emitters, stopped when their emitter goes away
+#[derive(Default)]
+pub(super) struct AmbientVoices(HashMap<Entity, AmbientVoice>);
+
+// start the sound of new emitters and stop the sound of removed ones
+#[allow(clippy::too_many_arguments)]
+pub(super) fn play_ambient(
+    mut voices: ResMut<AmbientVoices>,
+    registry: Res<SfxRegistry>,
+    settings: Res<AudioSettings>,
+    sources: Res<Assets<AudioSource>>,
+    mut spatial_sources: ResMut<Assets<SpatialSource>>,
+    audio: Res<Audio<SpatialSource>>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    emitters: Query<(Entity, &AmbientEmitter)>,
+    removed: RemovedComponents<AmbientEmitter>,
+) {
+    for entity in removed.iter() {
+        if let Some(voice) = voices.0.remove(&entity) {
+            if let Some(sink) = audio_sinks.get(&voice.sink) {
+                sink.stop();
+            }
+        }
+    }
+    for (entity, emitter) in emitters.iter() {
+        if voices.0.contains_key(&entity) {
+            continue;
+        }
+        // wait for the registry and the sound to load
+        let source = match registry
+            .source(&emitter.sound)
+            .and_then(|source| sources.get(source))
+        {
+            Some(source) => source.clone(),
+            None => continue,
+        };
+        let volume = emitter.volume * registry.get(&emitter.sound).map_or(1.0, |info| info.volume);
+        let gains = Arc::new(SpatialGains::default());
+        gains.set(0.0, 0.0);
+        let spatial = spatial_sources.add(SpatialSource {
+            source,
+            gains: gains.clone(),
+        });
+        let playback = PlaybackSettings {
+            volume: volume * settings.volume(AudioBus::Sfx),
+            ..PlaybackSettings::LOOP
+        };
+        let sink = audio.play_with_settings(spatial, playback);
+        voices.0.insert(
+            entity,
+            AmbientVoice {
+                sink: audio_sinks.get_handle(sink),
+                gains,
+                volume,
+            },
+        );
+    }
+}
+
+// pan and attenuate every placed sound relative to the camera focus
+pub(super) fn spatialize(
+    spatial: Res<SpatialSettings>,
+    settings: Res<AudioSettings>,
+    pool: Res<SfxPool>,
+    voices: Res<AmbientVoices>,
+    audio_sinks: Res<Assets<AudioSink>>,
+    cameras: Query<&CameraRig, With<MovableCamera>>,
+    emitters: Query<&GlobalTransform, With<AmbientEmitter>>,
+) {
+    let rig = match cameras.get_single() {
+        Ok(rig) => rig,
+        Err(_) => return,
+    };
+    for instance in &pool.instances {
+        if let (Some(position), Some(gains)) = (instance.position, &instance.gains) {
+            let (left, right) = spatial.gains(rig, position);
+            gains.set(left, right);
+        }
+    }
+    for (entity, voice) in &voices.0 {
+        if let Ok(transform) = emitters.get(*entity) {
+            let (left, right) = spatial.gains(rig, transform.translation);
+            voice.gains.set(left, right);
+        }
+        if settings.is_changed() {
+            if let Some(sink) = audio_sinks.get(&voice.sink) {
+                sink.set_volume(voice.volume * settings.volume(AudioBus::Sfx));
+            }
+        }
+    }
+}
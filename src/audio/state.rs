@@ -0,0 +1,100 @@
+use bevy::{prelude::*, window::WindowFocused};
+
+use super::{AudioSettings, MusicDirector};
+use crate::map::LevelState;
+
+// what the music is doing, from the strongest reason down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MusicState {
+    Playing,
+    Paused,
+    // quieter while a level loads or on `MusicEvent::Duck`
+    Ducked,
+    Stopped,
+}
+
+impl Default for MusicState {
+    fn default() -> Self {
+        MusicState::Playing
+    }
+}
+
+// requests to change the `MusicState`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MusicEvent {
+    // clear a pause or stop
+    Play,
+    Pause,
+    TogglePause,
+    // fade out, the track resumes where it was on `Play`
+    Stop,
+    Duck,
+    Unduck,
+}
+
+// reasons the music is not simply playing
+#[derive(Default)]
+pub(super) struct MusicHolds {
+    paused: bool,
+    stopped: bool,
+    ducked: bool,
+    focus_lost: bool,
+}
+
+pub(super) fn update_music_state(
+    mut events: EventReader<MusicEvent>,
+    mut focus_events: EventReader<WindowFocused>,
+    mut holds: Local<MusicHolds>,
+    settings: Res<AudioSettings>,
+    level_state: Res<State<LevelState>>,
+    mut state: ResMut<MusicState>,
+) {
+    for event in events.iter() {
+        match event {
+            MusicEvent::Play => {
+                holds.paused = false;
+                holds.stopped = false;
+            }
+            MusicEvent::Pause => holds.paused = true,
+            MusicEvent::TogglePause => holds.paused = !holds.paused,
+            MusicEvent::Stop => holds.stopped = true,
+            MusicEvent::Duck => holds.ducked = true,
+            MusicEvent::Unduck => holds.ducked = false,
+        }
+    }
+    for event in focus_events.iter() {
+        if event.id.is_primary() {
+            holds.focus_lost = !event.focused;
+        }
+    }
+
+    let loading = !matches!(level_state.current(), LevelState::Idle | LevelState::Active);
+    let next = if holds.stopped {
+        MusicState::Stopped
+    } else if holds.paused || (holds.focus_lost && settings.pause_on_focus_loss) {
+        MusicState::Paused
+    } else if holds.ducked || loading {
+        MusicState::Ducked
+    } else {
+        MusicState::Playing
+    };
+    if *state != next {
+        *state = next;
+    }
+}
+
+pub(super) fn apply_music_state(
+    state: Res<MusicState>,
+    settings: Res<AudioSettings>,
+    mut director: ResMut<MusicDirector>,
+) {
+    if !state.is_changed() && !settings.is_changed() {
+        return;
+    }
+    director.set_paused(*state == MusicState::Paused);
+    director.set_silenced(*state == MusicState::Stopped);
+    director.set_duck(match *state {
+        MusicState::Ducked => settings.duck_volume,
+        _ => 1.0,
+    });
+}
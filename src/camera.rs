@@ -1,62 +1,375 @@
-use bevy::prelude::*;
+use bevy::{
+    input::mouse::{MouseScrollUnit, MouseWheel},
+    prelude::*,
+};
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
 
+use crate::{
+    controls::{Action, ActionAxes},
+    map::MapQuery,
+    picking::Ray,
+};
+
 #[derive(Component)]
 pub struct MovableCamera;
 
 #[derive(Component, Inspectable)]
 struct Speed(f32);
 
+// the camera orbits `focus` on the ground in quarter turns
+#[derive(Component, Inspectable)]
+pub struct CameraRig {
+    pub focus: Vec3,
+    // quarter turns around the y axis
+    pub yaw: i32,
+    // height of the camera above the focus
+    pub height: f32,
+}
+
+impl CameraRig {
+    fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw as f32 * std::f32::consts::FRAC_PI_2)
+    }
+
+    // screen-up direction on the ground
+    pub fn forward(&self) -> Vec3 {
+        self.rotation() * (Vec3::X + Vec3::Z) / std::f32::consts::SQRT_2
+    }
+
+    // screen-right direction on the ground
+    pub fn right(&self) -> Vec3 {
+        self.forward().cross(Vec3::Y)
+    }
+
+    fn transform(&self) -> Transform {
+        let offset = self.rotation() * Vec3::new(-self.height, self.height, -self.height);
+        Transform::from_translation(self.focus + offset).looking_at(self.focus, Vec3::Y)
+    }
+}
+
+// entity followed by the camera in `CameraMode::Follow`
+#[derive(Component)]
+pub struct CameraTarget(pub Entity);
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraMode {
+    // moved with the pan actions and the mouse
+    Free,
+    // follows its `CameraTarget`
+    Follow,
+}
+
+// velocity of the smoothing and last position of the target
+#[derive(Component, Default)]
+struct FollowState {
+    velocity: Vec3,
+    target: Option<Vec3>,
+}
+
+pub struct CameraSettings {
+    // bounds of the orthographic projection scale
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    // change of the scale per wheel line
+    pub zoom_speed: f32,
+    // wheel lines per second with a trigger fully pressed
+    pub gamepad_zoom_rate: f32,
+    // time the follow mode takes to catch up with the target
+    pub smooth_time: f32,
+    // half extents along screen-right and screen-up the target moves in freely
+    pub dead_zone: Vec2,
+    // seconds of target movement the camera leads by
+    pub look_ahead: f32,
+    // keep the focus inside the bounds of the visible maps
+    pub clamp_to_map: bool,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        Self {
+            min_zoom: 2.0,
+            max_zoom: 30.0,
+            zoom_speed: 0.1,
+            gamepad_zoom_rate: 10.0,
+            smooth_time: 0.3,
+            dead_zone: Vec2::new(1.0, 1.0),
+            look_ahead: 0.5,
+            clamp_to_map: true,
+        }
+    }
+}
+
 pub struct CameraPlugin;
 
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Msaa { samples: 4 })
             .insert_resource(ClearColor(Color::rgb(10. / 255., 10. / 255., 10. / 255.)))
+            .init_resource::<CameraSettings>()
             .register_inspectable::<Speed>()
+            .register_inspectable::<CameraRig>()
             .add_startup_system(setup_camera)
-            .add_system(move_camera);
+            .add_system(toggle_camera_mode.before(CameraSystem::Move))
+            .add_system(move_camera.label(CameraSystem::Move))
+            .add_system(zoom_camera)
+            .add_system(pan_camera.label(CameraSystem::Move))
+            .add_system(rotate_camera.label(CameraSystem::Move))
+            .add_system(follow_target.label(CameraSystem::Move))
+            .add_system(
+                clamp_camera
+                    .label(CameraSystem::Clamp)
+                    .after(CameraSystem::Move),
+            )
+            .add_system(apply_camera_rig.after(CameraSystem::Clamp));
     }
 }
 
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum CameraSystem {
+    Move,
+    Clamp,
+}
+
 // set up a camera
 fn setup_camera(mut commands: Commands) {
     let position = Vec3::new(7.0, 20.0, 7.0);
     let height = position.y;
+    let rig = CameraRig {
+        focus: position + Vec3::new(height, -height, height),
+        yaw: 0,
+        height,
+    };
     let mut camera = OrthographicCameraBundle::new_3d();
     camera.orthographic_projection.scale = 10.0;
-    camera.transform = Transform::from_xyz(-height, height, -height)
-        .looking_at(Vec3::ZERO, Vec3::Y)
-        .with_translation(position);
+    camera.transform = rig.transform();
 
     commands
         .spawn_bundle(camera)
         .insert(Speed(15.0))
+        .insert(rig)
+        .insert(CameraMode::Free)
+        .insert(FollowState::default())
         .insert(MovableCamera);
 }
 
-// move camera by hjkl
+// switch between free and follow mode
+fn toggle_camera_mode(
+    actions: Res<Input<Action>>,
+    mut query: Query<&mut CameraMode, With<MovableCamera>>,
+) {
+    if actions.just_pressed(Action::ToggleFollow) {
+        let mut mode = query.single_mut();
+        *mode = match *mode {
+            CameraMode::Free => CameraMode::Follow,
+            CameraMode::Follow => CameraMode::Free,
+        };
+    }
+}
+
+// move camera by the pan actions and the left stick, relative to the current
+// rotation
 fn move_camera(
-    keyboard_input: Res<Input<KeyCode>>,
-    mut query: Query<(&mut Transform, &Speed), With<MovableCamera>>,
+    actions: Res<Input<Action>>,
+    axes: Res<ActionAxes>,
+    mut query: Query<(&mut CameraRig, &Speed, &CameraMode), With<MovableCamera>>,
     timer: Res<Time>,
 ) {
-    let (mut transform, speed) = query.single_mut();
-    let sqrt = std::f32::consts::SQRT_2;
+    let (mut rig, speed, mode) = query.single_mut();
+    if *mode != CameraMode::Free {
+        return;
+    }
+    let (forward, right) = (rig.forward(), rig.right());
 
-    if keyboard_input.pressed(KeyCode::J) {
-        transform.translation -= (Vec3::X + Vec3::Z) / sqrt * speed.0 * timer.delta_seconds();
+    if actions.pressed(Action::PanDown) {
+        rig.focus -= forward * speed.0 * timer.delta_seconds();
     }
 
-    if keyboard_input.pressed(KeyCode::K) {
-        transform.translation += (Vec3::X + Vec3::Z) / sqrt * speed.0 * timer.delta_seconds();
+    if actions.pressed(Action::PanUp) {
+        rig.focus += forward * speed.0 * timer.delta_seconds();
     }
 
-    if keyboard_input.pressed(KeyCode::H) {
-        transform.translation += (Vec3::X - Vec3::Z) / sqrt * speed.0 * timer.delta_seconds();
+    if actions.pressed(Action::PanLeft) {
+        rig.focus -= right * speed.0 * timer.delta_seconds();
     }
 
-    if keyboard_input.pressed(KeyCode::L) {
-        transform.translation -= (Vec3::X - Vec3::Z) / sqrt * speed.0 * timer.delta_seconds();
+    if actions.pressed(Action::PanRight) {
+        rig.focus += right * speed.0 * timer.delta_seconds();
+    }
+
+    if axes.pan != Vec2::ZERO {
+        rig.focus += (right * axes.pan.x + forward * axes.pan.y) * speed.0 * timer.delta_seconds();
+    }
+}
+
+// zoom with the mouse wheel and the triggers within the bounds of the settings
+fn zoom_camera(
+    settings: Res<CameraSettings>,
+    axes: Res<ActionAxes>,
+    timer: Res<Time>,
+    mut wheel_events: EventReader<MouseWheel>,
+    mut query: Query<&mut OrthographicProjection, With<MovableCamera>>,
+) {
+    let lines = wheel_events
+        .iter()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y,
+            // roughly one line per 20 pixels
+            MouseScrollUnit::Pixel => event.y / 20.0,
+        })
+        .sum::<f32>()
+        + axes.zoom * settings.gamepad_zoom_rate * timer.delta_seconds();
+    if lines == 0.0 {
+        return;
+    }
+    let mut projection = query.single_mut();
+    let scale = projection.scale * (1.0 - settings.zoom_speed).powf(lines);
+    projection.scale = scale.clamp(settings.min_zoom, settings.max_zoom);
+}
+
+// drag the ground while `DragPan` is held, the grabbed point stays under the
+// cursor
+fn pan_camera(
+    windows: Res<Windows>,
+    actions: Res<Input<Action>>,
+    mut grabbed: Local<Option<Vec3>>,
+    mut query: Query<(&mut CameraRig, &Camera, &GlobalTransform, &CameraMode), With<MovableCamera>>,
+) {
+    if !actions.pressed(Action::DragPan) {
+        *grabbed = None;
+        return;
+    }
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let (mut rig, camera, transform, mode) = query.single_mut();
+    if *mode != CameraMode::Free {
+        return;
+    }
+    let ground = window.cursor_position().and_then(|cursor| {
+        let ray = Ray::from_cursor(cursor, window, camera, transform);
+        ray.plane_intersection(rig.focus.y)
+    });
+    let ground = match ground {
+        Some(ground) => ground,
+        None => return,
+    };
+    match *grabbed {
+        Some(point) => rig.focus += point - ground,
+        None => *grabbed = Some(ground),
+    }
+}
+
+// orbit around the focus
+fn rotate_camera(
+    actions: Res<Input<Action>>,
+    mut query: Query<&mut CameraRig, With<MovableCamera>>,
+) {
+    let mut rig = query.single_mut();
+
+    if actions.just_pressed(Action::RotateLeft) {
+        rig.yaw = (rig.yaw + 1).rem_euclid(4);
+    }
+
+    if actions.just_pressed(Action::RotateRight) {
+        rig.yaw = (rig.yaw - 1).rem_euclid(4);
+    }
+}
+
+// smoothly keep the target inside the dead zone, ahead of its movement
+fn follow_target(
+    settings: Res<CameraSettings>,
+    timer: Res<Time>,
+    targets: Query<&GlobalTransform>,
+    mut query: Query<
+        (
+            &mut CameraRig,
+            &mut FollowState,
+            &CameraMode,
+            Option<&CameraTarget>,
+        ),
+        With<MovableCamera>,
+    >,
+) {
+    let (mut rig, mut state, mode, target) = query.single_mut();
+    let position = match target.and_then(|target| targets.get(target.0).ok()) {
+        Some(transform) if *mode == CameraMode::Follow => transform.translation,
+        _ => {
+            state.target = None;
+            return;
+        }
+    };
+    let delta = timer.delta_seconds();
+    if delta <= 0.0 {
+        return;
+    }
+    let target_velocity = state
+        .target
+        .map_or(Vec3::ZERO, |last| (position - last) / delta);
+    state.target = Some(position);
+
+    // only the part of the offset outside of the dead zone is followed
+    let lead = position + target_velocity * settings.look_ahead;
+    let offset = lead - rig.focus;
+    let (right, forward) = (rig.right(), rig.forward());
+    let excess = |offset: f32, half: f32| offset - offset.clamp(-half, half);
+    let goal = rig.focus
+        + right * excess(offset.dot(right), settings.dead_zone.x)
+        + forward * excess(offset.dot(forward), settings.dead_zone.y);
+
+    let focus = rig.focus;
+    rig.focus = smooth_damp(
+        focus,
+        goal,
+        &mut state.velocity,
+        settings.smooth_time,
+        delta,
+    );
+}
+
+// critically damped spring towards `goal`
+fn smooth_damp(
+    current: Vec3,
+    goal: Vec3,
+    velocity: &mut Vec3,
+    smooth_time: f32,
+    delta: f32,
+) -> Vec3 {
+    let omega = 2.0 / smooth_time.max(0.0001);
+    let x = omega * delta;
+    let decay = 1.0 / (1.0 + x + 0.48 * x * x + 0.235 * x * x * x);
+    let change = current - goal;
+    let temp = (*velocity + omega * change) * delta;
+    *velocity = (*velocity - omega * temp) * decay;
+    goal + (change + temp) * decay
+}
+
+// keep the focus inside the bounds of the visible maps
+fn clamp_camera(
+    settings: Res<CameraSettings>,
+    maps: MapQuery,
+    mut query: Query<&mut CameraRig, With<MovableCamera>>,
+) {
+    if !settings.clamp_to_map {
+        return;
+    }
+    let (min, max) = match maps.bounds() {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let mut rig = query.single_mut();
+    let focus = Vec2::new(rig.focus.x, rig.focus.z);
+    let clamped = focus.clamp(min, max);
+    if clamped != focus {
+        rig.focus.x = clamped.x;
+        rig.focus.z = clamped.y;
+    }
+}
+
+fn apply_camera_rig(
+    mut query: Query<(&mut Transform, &CameraRig), (With<MovableCamera>, Changed<CameraRig>)>,
+) {
+    for (mut transform, rig) in query.iter_mut() {
+        *transform = rig.transform();
     }
 }
@@ -0,0 +1,18 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+
+// read a settings file written by `save_ron`
+pub fn load_ron<T: DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let text = std::fs::read_to_string(path)?;
+    Ok(ron::from_str(&text)?)
+}
+
+// write a settings file, creating its folder
+pub fn save_ron<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let text = ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())?;
+    std::fs::write(path, text)?;
+    Ok(())
+}
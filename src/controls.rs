@@ -0,0 +1,367 @@
+use bevy::{input::InputSystem, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{load_ron, save_ron};
+
+// everything the player can do, bound to inputs through `InputMap<Action>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+    PanUp,
+    PanDown,
+    PanLeft,
+    PanRight,
+    // hold and move the mouse to drag the ground
+    DragPan,
+    RotateLeft,
+    RotateRight,
+    ToggleFollow,
+    Select,
+    ToggleMusic,
+    MusicVolumeDown,
+    MusicVolumeUp,
+    MuteAudio,
+    LoadLevel,
+    UnloadLevel,
+}
+
+// a physical input an action can be bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    // the button on any connected gamepad
+    Gamepad(GamepadButtonType),
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Mouse(button) => write!(f, "Mouse {:?}", button),
+            Binding::Gamepad(button) => write!(f, "Gamepad {:?}", button),
+        }
+    }
+}
+
+// bindings of every action, saved as ron
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InputMap<A: Ord> {
+    bindings: BTreeMap<A, Vec<Binding>>,
+}
+
+impl<A: Ord> Default for InputMap<A> {
+    fn default() -> Self {
+        Self {
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+impl<A: Ord + Copy> InputMap<A> {
+    pub fn bind(&mut self, action: A, binding: Binding) -> &mut Self {
+        let bindings = self.bindings.entry(action).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+        self
+    }
+
+    // replace every binding of an action
+    pub fn rebind(&mut self, action: A, binding: Binding) -> &mut Self {
+        self.bindings.insert(action, vec![binding]);
+        self
+    }
+
+    pub fn unbind(&mut self, action: A) -> &mut Self {
+        self.bindings.remove(&action);
+        self
+    }
+
+    pub fn bindings(&self, action: A) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (A, &[Binding])> + '_ {
+        self.bindings
+            .iter()
+            .map(|(action, bindings)| (*action, bindings.as_slice()))
+    }
+}
+
+impl<A: Ord + Serialize + DeserializeOwned> InputMap<A> {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        load_ron(path)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        save_ron(path, self)
+    }
+}
+
+impl InputMap<Action> {
+    pub fn defaults() -> Self {
+        let mut map = Self::default();
+        map.bind(Action::PanUp, Binding::Key(KeyCode::K))
+            .bind(Action::PanDown, Binding::Key(KeyCode::J))
+            .bind(Action::PanLeft, Binding::Key(KeyCode::H))
+            .bind(Action::PanRight, Binding::Key(KeyCode::L))
+            .bind(Action::DragPan, Binding::Mouse(MouseButton::Middle))
+            .bind(Action::RotateLeft, Binding::Key(KeyCode::Q))
+            .bind(Action::RotateRight, Binding::Key(KeyCode::E))
+            .bind(Action::ToggleFollow, Binding::Key(KeyCode::F))
+            .bind(Action::Select, Binding::Mouse(MouseButton::Left))
+            .bind(Action::ToggleMusic, Binding::Key(KeyCode::Space))
+            .bind(Action::MusicVolumeDown, Binding::Key(KeyCode::Minus))
+            .bind(Action::MusicVolumeUp, Binding::Key(KeyCode::Equals))
+            .bind(Action::MuteAudio, Binding::Key(KeyCode::M))
+            .bind(Action::LoadLevel, Binding::Key(KeyCode::P))
+            .bind(Action::UnloadLevel, Binding::Key(KeyCode::U))
+            .bind(
+                Action::RotateLeft,
+                Binding::Gamepad(GamepadButtonType::LeftTrigger),
+            )
+            .bind(
+                Action::RotateRight,
+                Binding::Gamepad(GamepadButtonType::RightTrigger),
+            )
+            .bind(
+                Action::ToggleFollow,
+                Binding::Gamepad(GamepadButtonType::West),
+            )
+            .bind(Action::Select, Binding::Gamepad(GamepadButtonType::South))
+            .bind(
+                Action::ToggleMusic,
+                Binding::Gamepad(GamepadButtonType::North),
+            )
+            .bind(
+                Action::LoadLevel,
+                Binding::Gamepad(GamepadButtonType::Start),
+            )
+            .bind(
+                Action::UnloadLevel,
+                Binding::Gamepad(GamepadButtonType::Select),
+            );
+        map
+    }
+}
+
+// tuning of the analog gamepad inputs
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AnalogSettings {
+    // stick and trigger values below this are ignored
+    pub dead_zone: f32,
+    pub pan_sensitivity: f32,
+    pub zoom_sensitivity: f32,
+}
+
+impl Default for AnalogSettings {
+    fn default() -> Self {
+        Self {
+            dead_zone: 0.15,
+            pan_sensitivity: 1.0,
+            zoom_sensitivity: 1.0,
+        }
+    }
+}
+
+impl AnalogSettings {
+    // rescale the values outside of the dead zone to start from 0
+    fn apply_dead_zone(&self, value: f32) -> f32 {
+        let dead_zone = self.dead_zone.clamp(0.0, 0.99);
+        if value.abs() <= dead_zone {
+            0.0
+        } else {
+            value.signum() * (value.abs() - dead_zone) / (1.0 - dead_zone)
+        }
+    }
+}
+
+// analog actions of the gamepads, after dead zone and sensitivity
+#[derive(Debug, Default)]
+pub struct ActionAxes {
+    // screen-right and screen-up, each within -1..=1 before sensitivity
+    pub pan: Vec2,
+    // positive zooms in
+    pub zoom: f32,
+}
+
+// where the bindings and analog settings are read from and saved to
+pub struct ControlsConfig {
+    pub path: PathBuf,
+    pub analog_path: PathBuf,
+}
+
+impl Default for ControlsConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("config/controls.ron"),
+            analog_path: PathBuf::from("config/analog.ron"),
+        }
+    }
+}
+
+// bind the next pressed input to the action, replacing its bindings
+pub struct RebindAction(pub Action);
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ActionSystem;
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ControlsConfig>()
+            .init_resource::<Input<Action>>()
+            .init_resource::<ActionAxes>()
+            .add_event::<RebindAction>()
+            .add_startup_system_to_stage(StartupStage::PreStartup, load_input_map)
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_actions.label(ActionSystem).after(InputSystem),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_axes.label(ActionSystem).after(InputSystem),
+            )
+            .add_system(rebind_action);
+    }
+}
+
+// read the bindings, falling back to the defaults
+fn load_input_map(mut commands: Commands, config: Res<ControlsConfig>) {
+    let input_map = match InputMap::<Action>::load(&config.path) {
+        Ok(mut input_map) => {
+            // actions added since the file was saved keep their defaults
+            for (action, bindings) in InputMap::defaults().iter() {
+                if input_map.bindings(action).is_empty() {
+                    for binding in bindings {
+                        input_map.bind(action, *binding);
+                    }
+                }
+            }
+            input_map
+        }
+        Err(error) => {
+            if config.path.exists() {
+                warn!("failed to read {}: {}", config.path.display(), error);
+            }
+            InputMap::defaults()
+        }
+    };
+    commands.insert_resource(input_map);
+
+    let analog = match load_ron::<AnalogSettings>(&config.analog_path) {
+        Ok(analog) => analog,
+        Err(error) => {
+            if config.analog_path.exists() {
+                warn!("failed to read {}: {}", config.analog_path.display(), error);
+            }
+            AnalogSettings::default()
+        }
+    };
+    commands.insert_resource(analog);
+}
+
+// press every action with at least one pressed binding
+fn update_actions(
+    input_map: Res<InputMap<Action>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    gamepads: Res<Gamepads>,
+    mut actions: ResMut<Input<Action>>,
+) {
+    actions.clear();
+    for (action, bindings) in input_map.iter() {
+        let pressed = bindings.iter().any(|binding| match *binding {
+            Binding::Key(key) => keyboard_input.pressed(key),
+            Binding::Mouse(button) => mouse_input.pressed(button),
+            Binding::Gamepad(button) => gamepads
+                .iter()
+                .any(|gamepad| gamepad_input.pressed(GamepadButton(*gamepad, button))),
+        });
+        if pressed {
+            actions.press(action);
+        } else {
+            actions.release(action);
+        }
+    }
+}
+
+// left stick pans, the right trigger zooms in and the left one out
+fn update_axes(
+    settings: Res<AnalogSettings>,
+    gamepads: Res<Gamepads>,
+    axes: Res<Axis<GamepadAxis>>,
+    buttons: Res<Axis<GamepadButton>>,
+    mut action_axes: ResMut<ActionAxes>,
+) {
+    let mut pan = Vec2::ZERO;
+    let mut zoom = 0.0;
+    for gamepad in gamepads.iter() {
+        let axis = |axis| axes.get(GamepadAxis(*gamepad, axis)).unwrap_or(0.0);
+        let button = |button| buttons.get(GamepadButton(*gamepad, button)).unwrap_or(0.0);
+        let stick = Vec2::new(
+            axis(GamepadAxisType::LeftStickX),
+            axis(GamepadAxisType::LeftStickY),
+        );
+        // radial dead zone so diagonals are not cut off
+        let length = settings.apply_dead_zone(stick.length().min(1.0));
+        pan += stick.normalize_or_zero() * length;
+        zoom += settings.apply_dead_zone(button(GamepadButtonType::RightTrigger2))
+            - settings.apply_dead_zone(button(GamepadButtonType::LeftTrigger2));
+    }
+    action_axes.pan = pan.clamp_length_max(1.0) * settings.pan_sensitivity;
+    action_axes.zoom = zoom.clamp(-1.0, 1.0) * settings.zoom_sensitivity;
+}
+
+// wait for an input after a `RebindAction` and save the new bindings
+fn rebind_action(
+    mut requests: EventReader<RebindAction>,
+    mut pending: Local<Option<Action>>,
+    config: Res<ControlsConfig>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+    mut input_map: ResMut<InputMap<Action>>,
+) {
+    if let Some(RebindAction(action)) = requests.iter().last() {
+        *pending = Some(*action);
+        return;
+    }
+    let action = match *pending {
+        Some(action) => action,
+        None => return,
+    };
+    let binding = keyboard_input
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse_input
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or_else(|| {
+            gamepad_input
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Gamepad(button.1))
+        });
+    if let Some(binding) = binding {
+        *pending = None;
+        input_map.rebind(action, binding);
+        info!("bound {:?} to {}", action, binding);
+        if let Err(error) = input_map.save(&config.path) {
+            warn!("failed to save {}: {}", config.path.display(), error);
+        }
+    }
+}
@@ -1,11 +1,18 @@
 /// Modules under lib.rs
 pub use audio::*;
 pub use camera::*;
+pub use controls::*;
 pub use map::*;
+pub use pathfinding::*;
+pub use picking::*;
 
 pub mod audio;
 pub mod camera;
+pub mod config;
+pub mod controls;
 pub mod map;
+pub mod pathfinding;
+pub mod picking;
 
 /// Crates for lib.rs
 use bevy::{app::*, prelude::*};
@@ -14,7 +21,13 @@ pub struct IndividualPlugins;
 
 impl PluginGroup for IndividualPlugins {
     fn build(&mut self, group: &mut PluginGroupBuilder) {
-        group.add(AudioPlugin).add(CameraPlugin).add(MapPlugin);
+        group
+            .add(AudioPlugin)
+            .add(CameraPlugin)
+            .add(ControlsPlugin)
+            .add(MapPlugin)
+            .add(PathfindingPlugin)
+            .add(PickingPlugin);
     }
 }
 
@@ -1,40 +1,78 @@
 use bevy::prelude::*;
-use bevy_rhai::*;
-use rhai::plugin::*;
+use bevy_rhai::StandardScope;
+use serde::Deserialize;
+
+use crate::{
+    audio::{AmbientEmitter, MapSfx, Playlist},
+    controls::Action,
+};
+
+pub use asset::MapAsset;
+pub use error::{MapLoadError, SourcePosition};
+pub use hooks::TileWalker;
+pub use level::{LevelId, LevelInfo, LevelManifest, LevelRegistry};
+pub use mesher::{MapChunk, MapMaterials};
+pub use overlay::MapErrors;
+pub use palette::{TileKind, TilePalette};
+pub use query::{Cell, MapGrid, MapQuery, MAX_STEP};
+pub use region::{RegionEntered, RegionExited, RegionTracker};
+pub use script::ScriptLimits;
+pub use transition::{ChangeLevel, LevelState, LevelTransition, StackLevel, UnloadLevels};
+
+use asset::{MapAssetLoader, MapLoadFailures, ReferencedMaps};
+use hooks::LevelScripts;
+use level::{build_level_registry, LevelManifestHandle, LevelManifestLoader};
+use mesher::{ChunkGeometry, MapMesher};
+use region::Region;
+use script::ScriptImports;
+use transition::{
+    despawn_outgoing, load_target, queue_level_requests, spawn_target, start_transition,
+    unload_outgoing,
+};
+
+mod asset;
+mod error;
+mod hooks;
+mod level;
+mod mesher;
+mod overlay;
+mod palette;
+mod query;
+mod region;
+mod script;
+mod transition;
+mod types;
 
 #[derive(Bundle)]
 struct LevelBundle {
-    level: Level,
+    level: LevelId,
     map: Map,
-    position: Position,
+    map_asset: Handle<MapAsset>,
     visible: Visible,
+    // kept while the level is registered, see `hooks`
+    scope: StandardScope,
 }
 
-#[derive(Component, PartialEq, Clone)]
-enum Level {
-    TestMap,
-}
-
-impl Level {
-    // bind levels to Python method names
-    fn name(&self) -> &str {
-        match *self {
-            Level::TestMap => "test_map",
-        }
-    }
-}
-
-#[derive(Component, Default, Debug, Clone)]
-struct Map {
+// public only because `MapQuery` reads it, its fields stay private
+#[derive(Component, Default, Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Map {
+    position: Vec3,
     floors: Vec<Floor>,
     stairs: Vec<Stair>,
     walls: Vec<Wall>,
+    ambient: Vec<Ambient>,
+    regions: Vec<Region>,
+    palette: TilePalette,
+    // BGM played while the level is the last one entered
+    music: Option<Playlist>,
+    sfx: MapSfx,
 }
 
 impl Map {
     fn width(&self) -> usize {
         let first_floor = self.floors.first().unwrap();
-        first_floor.data[0].len()
+        first_floor.data.first().map_or(0, Vec::len)
     }
 
     fn depth(&self) -> usize {
@@ -48,595 +86,612 @@ impl Map {
 
     fn new() -> Self {
         Map {
+            position: Vec3::ZERO,
             floors: Vec::new(),
             stairs: Vec::new(),
             walls: Vec::new(),
+            ambient: Vec::new(),
+            regions: Vec::new(),
+            palette: TilePalette::default(),
+            music: None,
+            sfx: MapSfx::default(),
         }
     }
 
     fn clear(&mut self) {
-        self.floors = Vec::new();
-        self.stairs = Vec::new();
-        self.walls = Vec::new();
+        *self = Map::new();
     }
 }
 
-#[derive(Component, Debug, Default, Clone)]
+#[derive(Component, Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Floor {
+    #[serde(default)]
     height: i32,
     data: Vec<Vec<i32>>,
 }
 
-impl Floor {
-    fn new() -> Self {
-        Self {
-            height: 0,
-            data: Vec::new(),
-        }
-    }
-}
-
-#[derive(Component, Debug, Clone)]
+#[derive(Component, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Stair {
     translation: Vec3,
+    #[serde(default)]
     direction: Direction,
+    #[serde(default = "Stair::default_scale")]
     scale: Vec3,
 }
 
 impl Stair {
-    fn new() -> Self {
-        Self {
-            translation: Vec3::ZERO,
-            direction: Direction::PZ,
-            scale: Vec3::ZERO,
-        }
+    fn default_scale() -> Vec3 {
+        Vec3::ONE
     }
 }
 
-#[derive(Component, Debug, Clone)]
+#[derive(Component, Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Wall {
     translation: Vec3,
+    #[serde(default)]
     direction: Direction,
     size: Vec2,
 }
 
-impl Wall {
-    fn new() -> Self {
-        Self {
-            translation: Vec3::ZERO,
-            direction: Direction::PZ,
-            size: Vec2::ZERO,
-        }
+// a looping sound placed on the map
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Ambient {
+    translation: Vec3,
+    // name in `sounds/sounds.sfx.ron`
+    sound: String,
+    #[serde(default = "Ambient::default_volume")]
+    volume: f32,
+}
+
+impl Ambient {
+    fn default_volume() -> f32 {
+        1.0
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
 enum Direction {
     PX,
     MX,
+    #[default]
     PZ,
     MZ,
 }
 
-#[derive(Component, Debug, Default, Clone)]
-struct Position(Vec3);
-
 #[derive(Component, Default)]
-struct Visible(bool);
+pub struct Visible(bool);
 
 #[derive(Component)]
 struct Tile;
 
-#[derive(Bundle, Default)]
-struct RhaiBundle {
-    engine: StandardEngine,
-    script_handle: Handle<StandardScript>,
-    scope: StandardScope,
+// sent when the map of a level could not be loaded
+#[derive(Debug, Clone)]
+pub struct MapLoadFailed {
+    pub level: LevelId,
+    // the script or data file, relative to the asset folder
+    pub path: String,
+    pub error: MapLoadError,
+}
+
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+enum MapSystem {
+    Registry,
+    Report,
+    Transition,
+    Regions,
+    Hooks,
 }
 
 pub struct MapPlugin;
 
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
-        app.add_startup_system(setup_levels)
-            .add_system(manual_load_map)
-            .add_system(manual_unload_map)
-            .add_system(manual_spawn_map)
-            .add_system(manual_despawn_map);
+        app.add_asset::<MapAsset>()
+            .init_asset_loader::<MapAssetLoader>()
+            .init_resource::<MapLoadFailures>()
+            .init_resource::<ReferencedMaps>()
+            .init_resource::<ScriptImports>()
+            .add_asset::<LevelManifest>()
+            .init_asset_loader::<LevelManifestLoader>()
+            .init_resource::<LevelRegistry>()
+            .init_resource::<MapMaterials>()
+            .add_event::<MapLoadFailed>()
+            .add_state(LevelState::Idle)
+            .init_resource::<LevelTransition>()
+            .add_event::<ChangeLevel>()
+            .add_event::<StackLevel>()
+            .add_event::<UnloadLevels>()
+            .add_startup_system(setup_levels)
+            .add_system(build_level_registry.label(MapSystem::Registry))
+            .add_system(sync_levels.after(MapSystem::Registry))
+            .add_system(script::reload_importers)
+            .add_system(report_map_failures.label(MapSystem::Report))
+            .init_resource::<MapErrors>()
+            .add_system(overlay::collect_map_errors.after(MapSystem::Report))
+            .add_system(overlay::show_map_errors)
+            .add_system(manual_change_level)
+            .init_resource::<LevelScripts>()
+            .add_system(
+                hooks::run_level_hooks
+                    .label(MapSystem::Hooks)
+                    .after(MapSystem::Transition),
+            )
+            .add_event::<RegionEntered>()
+            .add_event::<RegionExited>()
+            .add_system(region::detect_regions.label(MapSystem::Regions))
+            .add_system(
+                hooks::run_region_hooks
+                    .label(MapSystem::Hooks)
+                    .after(MapSystem::Regions),
+            )
+            .add_system(hooks::apply_host_commands.after(MapSystem::Hooks))
+            .add_system(queue_level_requests.before(MapSystem::Transition))
+            .init_resource::<ModifiedMaps>()
+            .add_system(collect_modified_maps.before(MapSystem::Transition))
+            .add_system_set(
+                SystemSet::on_update(LevelState::Idle)
+                    .label(MapSystem::Transition)
+                    .with_system(start_transition),
+            )
+            .add_system_set(
+                SystemSet::on_update(LevelState::Active)
+                    .label(MapSystem::Transition)
+                    .with_system(start_transition)
+                    .with_system(reload_modified_maps),
+            )
+            .add_system_set(
+                SystemSet::on_enter(LevelState::Despawning).with_system(despawn_outgoing),
+            )
+            .add_system_set(SystemSet::on_enter(LevelState::Unloading).with_system(unload_outgoing))
+            .add_system_set(SystemSet::on_update(LevelState::Loading).with_system(load_target))
+            .add_system_set(SystemSet::on_enter(LevelState::Spawning).with_system(spawn_target));
     }
 }
 
-// setup levels with empty maps
+// load the level manifest, levels are spawned once the registry is built
 fn setup_levels(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let handle: Handle<StandardScript> = asset_server.load("scripts/map_editor.rhai");
     asset_server.watch_for_changes().unwrap();
-    // test map
-    commands
-        .spawn_bundle(LevelBundle {
-            level: Level::TestMap,
-            map: Map::new(),
-            position: Position(Vec3::ZERO),
-            visible: Visible(false),
-        })
-        .insert_bundle(RhaiBundle {
-            engine: StandardEngine::with_engine({
-                let mut engine = Engine::new_raw();
-                engine.set_strict_variables(true);
-                engine.disable_symbol("eval");
-                engine
-            }),
-            script_handle: handle,
-            scope: StandardScope::default(),
-        })
-        .insert(Floor::default());
+    let handle = asset_server.load("manifest.levels.ron");
+    commands.insert_resource(LevelManifestHandle(handle));
 }
 
-#[allow(clippy::complexity)]
-fn manual_load_map(
-    keyboard_input: Res<Input<KeyCode>>,
-    scripts: Res<Assets<StandardScript>>,
-    query: Query<(
-        &mut Map,
-        &mut Position,
-        &StandardEngine,
-        &Handle<StandardScript>,
-        &mut StandardScope,
-    )>,
+// spawn a level with an empty map for every registered level
+fn sync_levels(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    registry: Res<LevelRegistry>,
+    referenced: Res<ReferencedMaps>,
+    levels: Query<(Entity, &LevelId), With<Map>>,
+    tiles: Query<(Entity, &LevelId), With<Tile>>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::P) {
-        let level_to_load = Level::TestMap;
-        load_map(&level_to_load, scripts, query);
+    if !registry.is_changed() {
+        return;
+    }
+    // before the maps are requested, so the loader knows which functions matter
+    referenced.set(registry.iter().map(LevelInfo::asset_path));
+    for info in registry.iter() {
+        let map_asset: Handle<MapAsset> = asset_server.load(info.asset_path().as_str());
+        match levels.iter().find(|(_, level)| **level == info.id) {
+            // the script path may have changed
+            Some((entity, _)) => {
+                commands.entity(entity).insert(map_asset);
+            }
+            None => {
+                commands
+                    .spawn_bundle(LevelBundle {
+                        level: info.id.clone(),
+                        map: Map::new(),
+                        map_asset,
+                        visible: Visible(false),
+                        scope: StandardScope::default(),
+                    })
+                    .insert(Floor::default());
+            }
+        }
+    }
+    // levels removed from the registry
+    for (entity, level) in levels.iter() {
+        if registry.get(level).is_some() {
+            continue;
+        }
+        commands.entity(entity).despawn();
+        for (tile, tile_level) in tiles.iter() {
+            if tile_level == level {
+                commands.entity(tile).despawn_recursive();
+            }
+        }
     }
 }
 
-#[allow(clippy::complexity)]
-fn load_map(
-    level_to_load: &Level,
-    scripts: Res<Assets<StandardScript>>,
-    mut query: Query<(
-        &mut Map,
-        &mut Position,
-        &StandardEngine,
-        &Handle<StandardScript>,
-        &mut StandardScope,
-    )>,
+// forward loader failures to the levels using the failed asset
+fn report_map_failures(
+    asset_server: Res<AssetServer>,
+    load_failures: Res<MapLoadFailures>,
+    query: Query<(&LevelId, &Handle<MapAsset>)>,
+    mut failures: EventWriter<MapLoadFailed>,
 ) {
-    for (mut map, mut position, engine, script, mut scope) in query.iter_mut() {
-        if let Some(script) = scripts.get(script) {
-            let result: rhai::Map = engine
-                .call_fn(&mut scope, &script.ast, level_to_load.name(), ())
-                .unwrap();
-            for (map_key, map_value) in result.iter() {
-                match map_key.as_str() {
-                    "position" => {
-                        let raw_position = map_value.clone_cast::<rhai::Array>();
-                        let vec: Vec<f32> = raw_position
-                            .into_iter()
-                            .map(|item| item.try_cast::<f32>().unwrap())
-                            .collect();
-                        position.0 = Vec3::new(vec[0], vec[1], vec[2]);
-                    }
-                    "floors" => {
-                        for raw_floor in map_value.clone_cast::<Vec<Dynamic>>().into_iter() {
-                            let mut temp_floor = Floor::new();
-                            let parsed_floor = raw_floor.try_cast::<rhai::Map>().unwrap();
-                            for (floor_key, floor_value) in parsed_floor.iter() {
-                                match floor_key.as_str() {
-                                    "height" => {
-                                        let height = floor_value.clone_cast::<i32>();
-                                        temp_floor.height = height;
-                                    }
-                                    "data" => {
-                                        let data = floor_value.clone_cast::<rhai::Array>();
-                                        temp_floor.data = data
-                                            .into_iter()
-                                            .map(|item| item.into_typed_array::<i32>().unwrap())
-                                            .collect();
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            map.floors.push(temp_floor);
-                        }
-                    }
-                    "stairs" => {
-                        for raw_stair in map_value.clone_cast::<Vec<Dynamic>>().into_iter() {
-                            let mut temp_stair = Stair::new();
-                            let parsed_stair = raw_stair.try_cast::<rhai::Map>().unwrap();
-                            for (stair_key, stair_value) in parsed_stair.iter() {
-                                match stair_key.as_str() {
-                                    "translation" => {
-                                        let raw_translation =
-                                            stair_value.clone_cast::<rhai::Array>();
-                                        let vec: Vec<f32> = raw_translation
-                                            .into_iter()
-                                            .map(|item| item.try_cast::<f32>().unwrap())
-                                            .collect();
-                                        temp_stair.translation = Vec3::new(vec[0], vec[1], vec[2]);
-                                    }
-                                    "direction" => {
-                                        let raw_direction = stair_value.clone_cast::<String>();
-                                        match raw_direction.as_str() {
-                                            "PX" => {
-                                                temp_stair.direction = Direction::PX;
-                                            }
-                                            "MX" => {
-                                                temp_stair.direction = Direction::MX;
-                                            }
-                                            "MZ" => {
-                                                temp_stair.direction = Direction::MZ;
-                                            }
-                                            _ => {
-                                                temp_stair.direction = Direction::PZ;
-                                            }
-                                        }
-                                    }
-                                    "scale" => {
-                                        let raw_scale = stair_value.clone_cast::<rhai::Array>();
-                                        let vec: Vec<f32> = raw_scale
-                                            .into_iter()
-                                            .map(|item| item.try_cast::<f32>().unwrap())
-                                            .collect();
-                                        temp_stair.scale = Vec3::new(vec[0], vec[1], vec[2]);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            map.stairs.push(temp_stair);
-                        }
-                    }
-                    "walls" => {
-                        for raw_wall in map_value.clone_cast::<Vec<Dynamic>>().into_iter() {
-                            let mut temp_wall = Wall::new();
-                            let parsed_wall = raw_wall.try_cast::<rhai::Map>().unwrap();
-                            for (wall_key, wall_value) in parsed_wall.iter() {
-                                match wall_key.as_str() {
-                                    "translation" => {
-                                        let raw_translation =
-                                            wall_value.clone_cast::<rhai::Array>();
-                                        let vec: Vec<f32> = raw_translation
-                                            .into_iter()
-                                            .map(|item| item.try_cast::<f32>().unwrap())
-                                            .collect();
-                                        temp_wall.translation = Vec3::new(vec[0], vec[1], vec[2]);
-                                    }
-                                    "direction" => {
-                                        let raw_direction = wall_value.clone_cast::<String>();
-                                        match raw_direction.as_str() {
-                                            "PX" => {
-                                                temp_wall.direction = Direction::PX;
-                                            }
-                                            "MX" => {
-                                                temp_wall.direction = Direction::MX;
-                                            }
-                                            "MZ" => {
-                                                temp_wall.direction = Direction::MZ;
-                                            }
-                                            _ => {
-                                                temp_wall.direction = Direction::PZ;
-                                            }
-                                        }
-                                    }
-                                    "size" => {
-                                        let raw_size = wall_value.clone_cast::<rhai::Array>();
-                                        let vec: Vec<f32> = raw_size
-                                            .into_iter()
-                                            .map(|item| item.try_cast::<f32>().unwrap())
-                                            .collect();
-                                        temp_wall.size = Vec2::new(vec[0], vec[1]);
-                                    }
-                                    _ => {}
-                                }
-                            }
-                            map.walls.push(temp_wall);
-                        }
-                    }
-                    _ => {}
-                }
+    for (path, error) in load_failures.drain() {
+        error!("failed to load map `{}`: {}", path.path().display(), error);
+        for (level, handle) in query.iter() {
+            let level_path = match asset_server.get_handle_path(handle) {
+                Some(level_path) => level_path,
+                None => continue,
+            };
+            // failures without a label affect every level in the file
+            if level_path.path() == path.path()
+                && (path.label().is_none() || level_path.label() == path.label())
+            {
+                failures.send(MapLoadFailed {
+                    level: level.clone(),
+                    path: path.path().display().to_string(),
+                    error: error.clone(),
+                });
             }
         }
     }
 }
 
-fn manual_unload_map(
-    keyboard_input: Res<Input<KeyCode>>,
-    query: Query<(&Level, &mut Map, &mut Position)>,
+// change to the start level or unload every level
+fn manual_change_level(
+    actions: Res<Input<Action>>,
+    registry: Res<LevelRegistry>,
+    mut changes: EventWriter<ChangeLevel>,
+    mut unloads: EventWriter<UnloadLevels>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::D) {
-        let level_to_unload = Level::TestMap;
-        unload_map(&level_to_unload, query);
+    if actions.just_pressed(Action::LoadLevel) {
+        if let Some(level) = registry.start_level() {
+            changes.send(ChangeLevel(level.clone()));
+        }
+    }
+    if actions.just_pressed(Action::UnloadLevel) {
+        unloads.send(UnloadLevels);
     }
 }
 
-// delete loaded map data
-fn unload_map(level_to_unload: &Level, mut query: Query<(&Level, &mut Map, &mut Position)>) {
-    for (level, mut map, mut position) in query.iter_mut() {
-        if level == level_to_unload {
-            map.clear();
-            position.0 = Vec3::ZERO;
-            return;
+// map assets modified since the last reload, kept while a transition runs so
+// the edit is applied once the levels are Active again
+#[derive(Default)]
+struct ModifiedMaps(Vec<Handle<MapAsset>>);
+
+fn collect_modified_maps(
+    mut events: EventReader<AssetEvent<MapAsset>>,
+    mut modified: ResMut<ModifiedMaps>,
+) {
+    for event in events.iter() {
+        // created again when `script::reload_importers` had it freed, a first
+        // load is skipped by `reload_modified_maps` as the level is not loaded
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            if !modified.0.contains(handle) {
+                modified.0.push(handle.clone_weak());
+            }
         }
     }
 }
 
-fn manual_spawn_map(
-    commands: Commands,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<StandardMaterial>>,
-    query: Query<(&Level, &Map, &Position, &mut Visible)>,
-    keyboard_input: Res<Input<KeyCode>>,
+// reload active levels when their asset changes on disk, only the chunks that
+// changed are rebuilt
+#[allow(clippy::complexity)]
+fn reload_modified_maps(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    materials: Res<MapMaterials>,
+    mut modified_maps: ResMut<ModifiedMaps>,
+    map_assets: Res<Assets<MapAsset>>,
+    mut levels: Query<(&LevelId, &Handle<MapAsset>, &mut Map, &Visible)>,
+    chunks: Query<(Entity, &LevelId, &MapChunk)>,
+    props: Query<(Entity, &LevelId), (With<Tile>, Without<MapChunk>)>,
 ) {
-    // manual event to spawn map
-    if keyboard_input.pressed(KeyCode::P) {
-        spawn_map(commands, meshes, materials, query);
+    for modified in std::mem::take(&mut modified_maps.0) {
+        for (level, handle, mut map, visible) in levels.iter_mut() {
+            if *handle != modified || !map.is_loaded() {
+                continue;
+            }
+            let map_asset = match map_assets.get(handle) {
+                Some(map_asset) => map_asset,
+                None => continue,
+            };
+            *map = map_asset.map.clone();
+            if visible.0 {
+                respawn_level(
+                    &mut commands,
+                    &mut meshes,
+                    &materials,
+                    level,
+                    &map,
+                    &chunks,
+                    &props,
+                );
+            }
+        }
     }
 }
 
-fn spawn_map(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut query: Query<(&Level, &Map, &Position, &mut Visible)>,
+// spawn the floor chunks, stairs and walls of a single level
+fn spawn_level(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &MapMaterials,
+    level: &LevelId,
+    map: &Map,
 ) {
-    let mesh = Mesh::from(shape::Cube { size: 1.0 });
-    let material = StandardMaterial::from(Color::rgb(230. / 255., 230. / 255., 230. / 255.));
+    // floors
+    for geometry in MapMesher::default().build(map) {
+        spawn_chunk(commands, meshes, materials, level, map, &geometry);
+    }
+    spawn_props(commands, materials, level, map);
+}
 
-    // spawn maps that are invisible and loaded
-    for (level, map, position, mut visible) in query.iter_mut() {
-        if visible.0 || !map.is_loaded() {
+// rebuild the floor chunks whose geometry changed and respawn stairs and walls
+fn respawn_level(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &MapMaterials,
+    level: &LevelId,
+    map: &Map,
+    chunks: &Query<(Entity, &LevelId, &MapChunk)>,
+    props: &Query<(Entity, &LevelId), (With<Tile>, Without<MapChunk>)>,
+) {
+    respawn_chunks(commands, meshes, materials, level, map, chunks);
+    for (entity, prop_level) in props.iter() {
+        if prop_level == level {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    spawn_props(commands, materials, level, map);
+}
+
+// rebuild the floor chunks whose geometry changed, the rest is kept
+fn respawn_chunks(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &MapMaterials,
+    level: &LevelId,
+    map: &Map,
+    chunks: &Query<(Entity, &LevelId, &MapChunk)>,
+) {
+    let mut geometries = MapMesher::default().build(map);
+    for (entity, chunk_level, chunk) in chunks.iter() {
+        if chunk_level != level {
             continue;
         }
-        // floors
-        let floors = map.floors.iter();
-        floors.for_each(|floor| {
-            let floor_height = floor.height as f32;
-            for i in 0..map.width() {
-                for j in 0..map.depth() {
-                    // -1 means no tile.
-                    if floor.data[j][i] == -1 {
-                        continue;
-                    }
-                    let tile_height = floor.data[j][i] as f32;
-                    let x = (map.width() - 1 - i) as f32 + position.0.x + 0.5;
-                    let z = (map.depth() - 1 - j) as f32 + position.0.z + 0.5;
-                    let y = tile_height / 2.0 + floor_height + position.0.y;
-                    let scale = Vec3::new(1.0, tile_height, 1.0);
-                    if floor.data[j][i] != 0 {
-                        commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(mesh.clone()),
-                                material: materials.add(material.clone()),
-                                transform: Transform::from_translation(Vec3::new(x, y, z))
-                                    .with_scale(scale),
-                                ..default()
-                            })
-                            .insert(Tile)
-                            .insert(level.clone());
-                    }
-                    commands
-                        .spawn_bundle(PbrBundle {
-                            mesh: meshes.add(mesh.clone()),
-                            material: materials.add(material.clone()),
-                            transform: Transform::from_translation(Vec3::new(x, -0.25, z))
-                                .with_scale(Vec3::new(1.0, 0.5, 1.0)),
-                            ..default()
-                        })
-                        .insert(Tile)
-                        .insert(level.clone());
-                }
+        let index = geometries
+            .iter()
+            .position(|geometry| geometry.coord == chunk.coord);
+        // unchanged chunks are kept as they are
+        if let Some(index) = index {
+            if geometries[index].hash() == chunk.hash {
+                geometries.swap_remove(index);
+                continue;
             }
-        });
-        // stairs
-        let stairs = map.stairs.iter();
-        stairs.for_each(|stair| {
-            let translation = stair.translation;
-            let scale = stair.scale;
-            match stair.direction {
-                Direction::PX => {
-                    let num = 3 * scale.x as usize;
-                    for i in 1..=num {
-                        let po = Vec3::new(
-                            (i - 1) as f32 / 3.0 + 1.0 / 6.0,
-                            i as f32 / 6.0 * scale.y / scale.x,
-                            scale.z / 2.0,
-                        );
-                        commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(mesh.clone()),
-                                material: materials.add(material.clone()),
-                                transform: Transform::from_translation(translation + po)
-                                    .with_scale(Vec3::new(
-                                        1.0 / 3.0,
-                                        i as f32 / 3.0 * scale.y / scale.x,
-                                        scale.z,
-                                    )),
-                                ..default()
-                            })
-                            .insert(Tile)
-                            .insert(level.clone());
-                    }
-                }
-                Direction::MX => {
-                    let num = 3 * scale.x as usize;
-                    for i in 1..=num {
-                        let po = Vec3::new(
-                            (i - 1) as f32 / 3.0 + 1.0 / 6.0,
-                            (num - i + 1) as f32 / 6.0 * scale.y / scale.x,
-                            scale.z / 2.0,
-                        );
-                        commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(mesh.clone()),
-                                material: materials.add(material.clone()),
-                                transform: Transform::from_translation(translation + po)
-                                    .with_scale(Vec3::new(
-                                        1.0 / 3.0,
-                                        (num - i + 1) as f32 / 3.0 * scale.y / scale.x,
-                                        scale.z,
-                                    )),
-                                ..default()
-                            })
-                            .insert(Tile)
-                            .insert(level.clone());
-                    }
-                }
-                Direction::PZ => {
-                    let num = 3 * scale.z as usize;
-                    for i in 1..=num {
-                        let po = Vec3::new(
-                            scale.x / 2.0,
-                            i as f32 / 6.0 * scale.y / scale.z,
-                            (i - 1) as f32 / 3.0 + 1.0 / 6.0,
-                        );
-                        commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(mesh.clone()),
-                                material: materials.add(material.clone()),
-                                transform: Transform::from_translation(translation + po)
-                                    .with_scale(Vec3::new(
-                                        scale.x,
-                                        i as f32 / 3.0 * scale.y / scale.z,
-                                        1.0 / 3.0,
-                                    )),
-                                ..default()
-                            })
-                            .insert(Tile)
-                            .insert(level.clone());
-                    }
-                }
-                Direction::MZ => {
-                    let num = 3 * scale.z as usize;
-                    for i in 1..=num {
-                        let po = Vec3::new(
-                            scale.x / 2.0,
-                            (num - i + 1) as f32 / 6.0 * scale.y / scale.z,
-                            (i - 1) as f32 / 3.0 + 1.0 / 6.0,
-                        );
-                        commands
-                            .spawn_bundle(PbrBundle {
-                                mesh: meshes.add(mesh.clone()),
-                                material: materials.add(material.clone()),
-                                transform: Transform::from_translation(translation + po)
-                                    .with_scale(Vec3::new(
-                                        scale.x,
-                                        (num - i + 1) as f32 / 3.0 * scale.y / scale.z,
-                                        1.0 / 3.0,
-                                    )),
-                                ..default()
-                            })
-                            .insert(Tile)
-                            .insert(level.clone());
-                    }
-                }
+        }
+        commands.entity(entity).despawn_recursive();
+    }
+    for geometry in geometries {
+        spawn_chunk(commands, meshes, materials, level, map, &geometry);
+    }
+}
+
+// one child per tile material of the chunk
+fn spawn_chunk(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &MapMaterials,
+    level: &LevelId,
+    map: &Map,
+    geometry: &ChunkGeometry,
+) {
+    commands
+        .spawn_bundle(TransformBundle::identity())
+        .insert(MapChunk {
+            coord: geometry.coord,
+            hash: geometry.hash(),
+        })
+        .insert(Tile)
+        .insert(level.clone())
+        .with_children(|parent| {
+            for (slot, data) in &geometry.parts {
+                let material = map.palette.material(*slot).unwrap_or(&materials.tile);
+                parent.spawn_bundle(PbrBundle {
+                    mesh: meshes.add(data.to_mesh()),
+                    material: material.clone(),
+                    ..default()
+                });
             }
         });
-        // walls
-        let walls = map.walls.iter();
-        walls.for_each(|wall| {
-            let translation = wall.translation;
-            let size = wall.size;
-            let material =
-                StandardMaterial::from(Color::rgb(230. / 255., 230. / 255., 230. / 255.));
-            match wall.direction {
-                Direction::PX => {
-                    let material = StandardMaterial::from(Color::rgb(0.0, 0.0, 0.0));
-                    let offset = Vec3::new(-0.01, size.y / 2.0 - 0.5, size.x / 2.0);
-                    let mesh = Mesh::from(shape::Quad { size, flip: false });
+}
+
+// spawn the stairs and walls of a level
+fn spawn_props(commands: &mut Commands, materials: &MapMaterials, level: &LevelId, map: &Map) {
+    // stairs
+    let stairs = map.stairs.iter();
+    stairs.for_each(|stair| {
+        let translation = stair.translation;
+        let scale = stair.scale;
+        match stair.direction {
+            Direction::PX => {
+                let num = 3 * scale.x as usize;
+                for i in 1..=num {
+                    let po = Vec3::new(
+                        (i - 1) as f32 / 3.0 + 1.0 / 6.0,
+                        i as f32 / 6.0 * scale.y / scale.x,
+                        scale.z / 2.0,
+                    );
                     commands
                         .spawn_bundle(PbrBundle {
-                            mesh: meshes.add(mesh),
-                            material: materials.add(material),
-                            transform: Transform::from_rotation(Quat::from_rotation_y(
-                                -std::f32::consts::FRAC_PI_2,
-                            ))
-                            .with_translation(translation + offset),
+                            mesh: materials.cube.clone(),
+                            material: materials.tile.clone(),
+                            transform: Transform::from_translation(translation + po).with_scale(
+                                Vec3::new(1.0 / 3.0, i as f32 / 3.0 * scale.y / scale.x, scale.z),
+                            ),
                             ..default()
                         })
                         .insert(Tile)
                         .insert(level.clone());
                 }
-                Direction::MX => {
-                    let offset = Vec3::new(0.0, size.y / 2.0 - 0.5, size.x / 2.0);
-                    let mesh = Mesh::from(shape::Quad { size, flip: false });
+            }
+            Direction::MX => {
+                let num = 3 * scale.x as usize;
+                for i in 1..=num {
+                    let po = Vec3::new(
+                        (i - 1) as f32 / 3.0 + 1.0 / 6.0,
+                        (num - i + 1) as f32 / 6.0 * scale.y / scale.x,
+                        scale.z / 2.0,
+                    );
                     commands
                         .spawn_bundle(PbrBundle {
-                            mesh: meshes.add(mesh),
-                            material: materials.add(material),
-                            transform: Transform::from_rotation(Quat::from_rotation_y(
-                                -std::f32::consts::FRAC_PI_2,
-                            ))
-                            .with_translation(translation + offset),
+                            mesh: materials.cube.clone(),
+                            material: materials.tile.clone(),
+                            transform: Transform::from_translation(translation + po).with_scale(
+                                Vec3::new(
+                                    1.0 / 3.0,
+                                    (num - i + 1) as f32 / 3.0 * scale.y / scale.x,
+                                    scale.z,
+                                ),
+                            ),
                             ..default()
                         })
                         .insert(Tile)
                         .insert(level.clone());
                 }
-                Direction::PZ => {
-                    let material = StandardMaterial::from(Color::rgb(0.0, 0.0, 0.0));
-                    let offset = Vec3::new(size.x / 2.0, size.y / 2.0 - 0.5, -0.01);
-                    let mesh = Mesh::from(shape::Quad { size, flip: false });
+            }
+            Direction::PZ => {
+                let num = 3 * scale.z as usize;
+                for i in 1..=num {
+                    let po = Vec3::new(
+                        scale.x / 2.0,
+                        i as f32 / 6.0 * scale.y / scale.z,
+                        (i - 1) as f32 / 3.0 + 1.0 / 6.0,
+                    );
                     commands
                         .spawn_bundle(PbrBundle {
-                            mesh: meshes.add(mesh),
-                            material: materials.add(material),
-                            transform: Transform::from_rotation(Quat::from_rotation_y(
-                                std::f32::consts::PI,
-                            ))
-                            .with_translation(translation + offset),
+                            mesh: materials.cube.clone(),
+                            material: materials.tile.clone(),
+                            transform: Transform::from_translation(translation + po).with_scale(
+                                Vec3::new(scale.x, i as f32 / 3.0 * scale.y / scale.z, 1.0 / 3.0),
+                            ),
                             ..default()
                         })
                         .insert(Tile)
                         .insert(level.clone());
                 }
-                Direction::MZ => {
-                    let offset = Vec3::new(size.x / 2.0, size.y / 2.0 - 0.5, 0.0);
-                    let mesh = Mesh::from(shape::Quad { size, flip: false });
+            }
+            Direction::MZ => {
+                let num = 3 * scale.z as usize;
+                for i in 1..=num {
+                    let po = Vec3::new(
+                        scale.x / 2.0,
+                        (num - i + 1) as f32 / 6.0 * scale.y / scale.z,
+                        (i - 1) as f32 / 3.0 + 1.0 / 6.0,
+                    );
                     commands
                         .spawn_bundle(PbrBundle {
-                            mesh: meshes.add(mesh),
-                            material: materials.add(material),
-                            transform: Transform::from_rotation(Quat::from_rotation_y(
-                                std::f32::consts::PI,
-                            ))
-                            .with_translation(translation + offset),
-                            visibility: Visibility { is_visible: false },
+                            mesh: materials.cube.clone(),
+                            material: materials.tile.clone(),
+                            transform: Transform::from_translation(translation + po).with_scale(
+                                Vec3::new(
+                                    scale.x,
+                                    (num - i + 1) as f32 / 3.0 * scale.y / scale.z,
+                                    1.0 / 3.0,
+                                ),
+                            ),
                             ..default()
                         })
                         .insert(Tile)
                         .insert(level.clone());
                 }
             }
-        });
-        visible.0 = true;
-    }
-}
-
-fn manual_despawn_map(
-    commands: Commands,
-    query: Query<(Entity, &Level, Option<&mut Visible>, Option<&Tile>)>,
-    keyboard_input: Res<Input<KeyCode>>,
-) {
-    if keyboard_input.just_pressed(KeyCode::D) {
-        let level_to_despawn = Level::TestMap;
-        despawn_map(commands, query, &level_to_despawn);
-    }
-}
-
-fn despawn_map(
-    mut commands: Commands,
-    mut query: Query<(Entity, &Level, Option<&mut Visible>, Option<&Tile>)>,
-    level_to_despawn: &Level,
-) {
-    for (entity, level, visible, tile) in query.iter_mut() {
-        if level == level_to_despawn {
-            // despawn tiles
-            if tile.is_some() {
-                commands.entity(entity).despawn_recursive();
+        }
+    });
+    // walls
+    let walls = map.walls.iter();
+    walls.for_each(|wall| {
+        let translation = wall.translation;
+        let size = wall.size;
+        let scale = size.extend(1.0);
+        match wall.direction {
+            Direction::PX => {
+                let offset = Vec3::new(-0.01, size.y / 2.0 - 0.5, size.x / 2.0);
+                commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: materials.quad.clone(),
+                        material: materials.dark_wall.clone(),
+                        transform: Transform::from_rotation(Quat::from_rotation_y(
+                            -std::f32::consts::FRAC_PI_2,
+                        ))
+                        .with_translation(translation + offset)
+                        .with_scale(scale),
+                        ..default()
+                    })
+                    .insert(Tile)
+                    .insert(level.clone());
             }
-            // set the map to be not visible
-            if let Some(mut visible) = visible {
-                visible.0 = false;
+            Direction::MX => {
+                let offset = Vec3::new(0.0, size.y / 2.0 - 0.5, size.x / 2.0);
+                commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: materials.quad.clone(),
+                        material: materials.tile.clone(),
+                        transform: Transform::from_rotation(Quat::from_rotation_y(
+                            -std::f32::consts::FRAC_PI_2,
+                        ))
+                        .with_translation(translation + offset)
+                        .with_scale(scale),
+                        ..default()
+                    })
+                    .insert(Tile)
+                    .insert(level.clone());
+            }
+            Direction::PZ => {
+                let offset = Vec3::new(size.x / 2.0, size.y / 2.0 - 0.5, -0.01);
+                commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: materials.quad.clone(),
+                        material: materials.dark_wall.clone(),
+                        transform: Transform::from_rotation(Quat::from_rotation_y(
+                            std::f32::consts::PI,
+                        ))
+                        .with_translation(translation + offset)
+                        .with_scale(scale),
+                        ..default()
+                    })
+                    .insert(Tile)
+                    .insert(level.clone());
+            }
+            Direction::MZ => {
+                let offset = Vec3::new(size.x / 2.0, size.y / 2.0 - 0.5, 0.0);
+                commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: materials.quad.clone(),
+                        material: materials.tile.clone(),
+                        transform: Transform::from_rotation(Quat::from_rotation_y(
+                            std::f32::consts::PI,
+                        ))
+                        .with_translation(translation + offset)
+                        .with_scale(scale),
+                        visibility: Visibility { is_visible: false },
+                        ..default()
+                    })
+                    .insert(Tile)
+                    .insert(level.clone());
             }
         }
-    }
+    });
+    // ambient sounds
+    let ambient = map.ambient.iter();
+    ambient.for_each(|ambient| {
+        commands
+            .spawn_bundle(TransformBundle::from_transform(
+                Transform::from_translation(ambient.translation),
+            ))
+            .insert(AmbientEmitter {
+                sound: ambient.sound.clone(),
+                volume: ambient.volume,
+            })
+            .insert(Tile)
+            .insert(level.clone());
+    });
 }
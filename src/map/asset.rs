@@ -0,0 +1,403 @@
+use bevy::{
+    asset::{
+        AssetLoader, AssetPath, AssetServerSettings, BoxedFuture, FileAssetIo, LoadContext,
+        LoadedAsset,
+    },
+    prelude::*,
+    reflect::TypeUuid,
+};
+use rhai::{
+    packages::{Package, StandardPackage},
+    serde::DynamicDeserializer,
+    Dynamic, Engine, EvalAltResult, FnAccess, ParseError, Scope, AST,
+};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    hooks,
+    script::{self, ModuleTracker, ScriptImports},
+    types, Map, MapGrid, MapLoadError, ScriptLimits, SourcePosition, TilePalette,
+};
+
+// an error and the label of the map it belongs to
+type LabeledError = (Option<String>, MapLoadError);
+
+// the maps of a file and the hooks of its levels
+struct MapFile {
+    maps: Vec<(Option<String>, Map)>,
+    hooks: Option<AST>,
+}
+
+// map data produced by a `.map.rhai` script or a `.map.ron` data file
+//
+// scripts produce the value of their last statement as the default asset and
+// one labeled asset per public function without parameters, so a level can
+// refer to `scripts/map_editor.map.rhai#test_map`. hooks such as `on_enter`
+// are not maps, they are kept for `hooks::run_level_hooks`.
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "f567d459-fa53-47bc-abff-883066060783"]
+pub struct MapAsset {
+    pub(super) map: Map,
+    // the hook functions of the script, shared by all of its maps
+    pub(super) hooks: Option<AST>,
+}
+
+impl MapAsset {
+    // evaluate a map script without the asset server, `function` picks one of
+    // its labeled maps. palette data files are not read and imports fail.
+    pub fn from_script(source: &str, function: Option<&str>) -> Result<Self, MapLoadError> {
+        let engine = map_engine(&ScriptLimits::default(), None);
+        let referenced = |name: &str| Some(name) == function;
+        let file =
+            load_script(&engine, source.as_bytes(), referenced).map_err(|(_, error)| error)?;
+        let hooks = file.hooks;
+        file.maps
+            .into_iter()
+            .find(|(label, _)| label.as_deref() == function)
+            .map(|(_, map)| MapAsset { map, hooks })
+            .ok_or_else(|| MapLoadError::MissingFunction {
+                function: function.unwrap_or_default().to_string(),
+            })
+    }
+
+    pub fn grid(&self) -> MapGrid {
+        MapGrid::new(&self.map)
+    }
+}
+
+// failures reported by the loader, drained into `MapLoadFailed` events
+#[derive(Default, Clone)]
+pub(super) struct MapLoadFailures(Arc<Mutex<Vec<(AssetPath<'static>, MapLoadError)>>>);
+
+impl MapLoadFailures {
+    fn push(&self, path: AssetPath<'static>, error: MapLoadError) {
+        self.0.lock().unwrap().push((path, error));
+    }
+
+    pub(super) fn drain(&self) -> Vec<(AssetPath<'static>, MapLoadError)> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// the labeled map assets of the registered levels, `scripts/a.map.rhai#test_map`,
+// updated by `sync_levels` and read by the loader
+#[derive(Default, Clone)]
+pub(super) struct ReferencedMaps(Arc<Mutex<HashSet<String>>>);
+
+impl ReferencedMaps {
+    pub(super) fn set(&self, paths: impl Iterator<Item = String>) {
+        *self.0.lock().unwrap() = paths.collect();
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        self.0.lock().unwrap().contains(path)
+    }
+}
+
+pub(super) struct MapAssetLoader {
+    limits: ScriptLimits,
+    failures: MapLoadFailures,
+    imports: ScriptImports,
+    referenced: ReferencedMaps,
+    // the asset folder on disk, modules are imported from its `scripts/`
+    asset_root: PathBuf,
+}
+
+impl FromWorld for MapAssetLoader {
+    fn from_world(world: &mut World) -> Self {
+        let failures = world
+            .get_resource_or_insert_with(MapLoadFailures::default)
+            .clone();
+        let limits = world
+            .get_resource_or_insert_with(ScriptLimits::default)
+            .clone();
+        let imports = world
+            .get_resource_or_insert_with(ScriptImports::default)
+            .clone();
+        let asset_folder = world
+            .get_resource::<AssetServerSettings>()
+            .map_or("assets".to_string(), |settings| {
+                settings.asset_folder.clone()
+            });
+        let asset_root = FileAssetIo::get_root_path().join(asset_folder);
+        let referenced = world
+            .get_resource_or_insert_with(ReferencedMaps::default)
+            .clone();
+        Self {
+            limits,
+            failures,
+            imports,
+            referenced,
+            asset_root,
+        }
+    }
+}
+
+impl AssetLoader for MapAssetLoader {
+    fn extensions(&self) -> &[&str] {
+        &["map.rhai", "map.ron"]
+    }
+
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let path = load_context.path().to_path_buf();
+            let file = if path.to_string_lossy().ends_with(".ron") {
+                load_data(bytes)
+            } else {
+                let map_file = self.asset_root.join(&path);
+                self.imports.clear(&map_file);
+                let modules = ModuleTracker::new(
+                    &self.asset_root.join("scripts"),
+                    map_file,
+                    self.imports.clone(),
+                );
+                let referenced = |function: &str| {
+                    let label = format!("{}#{}", path.to_string_lossy(), function);
+                    self.referenced.contains(&label)
+                };
+                // a fresh engine per file, the time limit starts with it
+                load_script(&map_engine(&self.limits, Some(modules)), bytes, referenced)
+            };
+            let result = match file {
+                Ok(file) => add_maps(file, load_context).await,
+                Err(error) => Err(error),
+            };
+            result.map_err(|(label, error)| {
+                self.failures
+                    .push(AssetPath::new(path, label), error.clone());
+                anyhow::Error::new(error)
+            })
+        })
+    }
+}
+
+// the value of the last statement and every public function without parameters
+// that is not a hook. functions no level is `referenced` by are skipped when
+// they fail or return something that is not a map.
+fn load_script(
+    engine: &Engine,
+    bytes: &[u8],
+    referenced: impl Fn(&str) -> bool,
+) -> Result<MapFile, LabeledError> {
+    let source = std::str::from_utf8(bytes).map_err(|error| {
+        let message = error.to_string();
+        let position = None;
+        (None, MapLoadError::Parse { message, position })
+    })?;
+    let ast = engine
+        .compile(source)
+        .map_err(|error| (None, parse_error(error)))?;
+
+    // the last statement of the script
+    let mut scope = Scope::new();
+    let result = engine
+        .eval_ast_with_scope::<Dynamic>(&mut scope, &ast)
+        .map_err(|error| (None, script_error(None, error)))?;
+    let mut maps = Vec::new();
+    if let Some(map) = parse_map(result).map_err(|error| (None, error))? {
+        maps.push((None, map));
+    }
+
+    // public functions without parameters
+    let functions: Vec<String> = ast
+        .iter_functions()
+        .filter(|function| function.access == FnAccess::Public)
+        .filter(|function| function.params.is_empty())
+        .filter(|function| !hooks::is_hook(&function.name))
+        .map(|function| function.name.to_string())
+        .collect();
+    for name in functions {
+        let map = engine
+            .call_fn::<Dynamic>(&mut scope, &ast, &name, ())
+            .map_err(|error| script_error(Some(name.clone()), error))
+            .and_then(parse_map);
+        match map {
+            Ok(Some(map)) => maps.push((Some(name), map)),
+            Ok(None) => {}
+            Err(error) if referenced(&name) => return Err((Some(name), error)),
+            // a helper of the script, not a map of any level
+            Err(error) => debug!("skipped function `{}`: {}", name, error),
+        }
+    }
+    let hooks = hooks::hooks_ast(&ast).map_err(|error| (None, error))?;
+    Ok(MapFile { maps, hooks })
+}
+
+fn load_data(bytes: &[u8]) -> Result<MapFile, LabeledError> {
+    let mut deserializer = ron::Deserializer::from_bytes(bytes).map_err(|error| {
+        let message = error.code.to_string();
+        let position = Some(SourcePosition {
+            line: error.position.line,
+            column: error.position.col,
+        });
+        (None, MapLoadError::Parse { message, position })
+    })?;
+    let map: Map = serde_path_to_error::deserialize(&mut deserializer).map_err(|error| {
+        let path = error.path().to_string();
+        let message = error.inner().to_string();
+        (None, MapLoadError::Invalid { path, message })
+    })?;
+    validate_floors(&map).map_err(|error| (None, error))?;
+    let maps = vec![(None, map)];
+    Ok(MapFile { maps, hooks: None })
+}
+
+// store the maps of a file as its default and labeled assets
+async fn add_maps(file: MapFile, load_context: &mut LoadContext<'_>) -> Result<(), LabeledError> {
+    for (label, mut map) in file.maps {
+        load_palette(&mut map.palette, label.as_deref(), load_context)
+            .await
+            .map_err(|error| (label.clone(), error))?;
+        let hooks = file.hooks.clone();
+        let asset = LoadedAsset::new(MapAsset { map, hooks });
+        match label {
+            Some(label) => {
+                load_context.set_labeled_asset(&label, asset);
+            }
+            None => load_context.set_default_asset(asset),
+        }
+    }
+    Ok(())
+}
+
+// read the palette data file and add one labeled material per tile kind
+async fn load_palette(
+    palette: &mut TilePalette,
+    label: Option<&str>,
+    load_context: &mut LoadContext<'_>,
+) -> Result<(), MapLoadError> {
+    if let Some(file) = palette.file.clone() {
+        let palette_error = |message: String| MapLoadError::Palette {
+            file: file.clone(),
+            message,
+        };
+        let bytes = load_context
+            .read_asset_bytes(&file)
+            .await
+            .map_err(|error| palette_error(error.to_string()))?;
+        let mut deserializer = ron::Deserializer::from_bytes(&bytes)
+            .map_err(|error| palette_error(error.to_string()))?;
+        let data: TilePalette = serde_path_to_error::deserialize(&mut deserializer)
+            .map_err(|error| palette_error(format!("{}: {}", error.path(), error.inner())))?;
+        palette.merge_file(data);
+    }
+
+    let mut materials = Vec::with_capacity(palette.tiles.len());
+    for (index, kind) in palette.tiles.iter().enumerate() {
+        let [r, g, b] = kind.color;
+        let mut material = StandardMaterial::from(Color::rgb(r, g, b));
+        let mut dependency = None;
+        if let (Some(atlas), Some(_)) = (&palette.atlas, kind.texture) {
+            let atlas = AssetPath::from(atlas.as_str());
+            material.base_color_texture = Some(load_context.get_handle(atlas.clone()));
+            dependency = Some(atlas);
+        }
+        let mut asset = LoadedAsset::new(material);
+        if let Some(atlas) = dependency {
+            asset = asset.with_dependency(atlas);
+        }
+        let name = match label {
+            Some(label) => format!("{}/palette/{}", label, index),
+            None => format!("palette/{}", index),
+        };
+        materials.push(load_context.set_labeled_asset(&name, asset));
+    }
+    palette.materials = materials;
+    Ok(())
+}
+
+fn parse_error(error: ParseError) -> MapLoadError {
+    MapLoadError::Parse {
+        message: error.err_type().to_string(),
+        position: SourcePosition::from_rhai(error.position()),
+    }
+}
+
+fn script_error(function: Option<String>, mut error: Box<EvalAltResult>) -> MapLoadError {
+    let position = SourcePosition::from_rhai(error.take_position());
+    let message = match *error {
+        // the reason given by `ScriptLimits::apply`
+        EvalAltResult::ErrorTerminated(ref reason, _) => reason.to_string(),
+        ref error => error.to_string(),
+    };
+    MapLoadError::Script {
+        function,
+        message,
+        position,
+    }
+}
+
+// engine used to evaluate map scripts, with the standard packages, the map
+// helpers and the sandbox limits
+fn map_engine(limits: &ScriptLimits, modules: Option<ModuleTracker>) -> Engine {
+    let mut engine = Engine::new_raw();
+    limits.apply(&mut engine);
+    configure_engine(&mut engine);
+    if let Some(modules) = modules {
+        engine.set_module_resolver(modules);
+    }
+    engine
+}
+
+// the packages, helpers and types of map scripts and their hooks
+pub(super) fn configure_engine(engine: &mut Engine) {
+    StandardPackage::new().register_into_engine(engine);
+    script::register_helpers(engine);
+    types::register_types(engine);
+    engine.set_strict_variables(true);
+    engine.disable_symbol("eval");
+}
+
+// the `Map` or the object map returned by a map script, other values are not maps
+fn parse_map(result: Dynamic) -> Result<Option<Map>, MapLoadError> {
+    let map = if result.is::<Map>() {
+        result.cast::<Map>()
+    } else if result.is::<rhai::Map>() {
+        let result = types::to_object_form(result);
+        serde_path_to_error::deserialize(DynamicDeserializer::new(&result)).map_err(|error| {
+            MapLoadError::Invalid {
+                path: error.path().to_string(),
+                message: error.inner().to_string(),
+            }
+        })?
+    } else {
+        return Ok(None);
+    };
+    validate_floors(&map)?;
+    Ok(Some(map))
+}
+
+// every floor must have the same size as the first one
+fn validate_floors(map: &Map) -> Result<(), MapLoadError> {
+    if !map.is_loaded() {
+        return Ok(());
+    }
+    let (width, depth) = (map.width(), map.depth());
+    for (index, floor) in map.floors.iter().enumerate() {
+        if floor.data.len() != depth {
+            return Err(MapLoadError::RaggedFloor {
+                path: format!("floors[{}].data", index),
+                expected: depth,
+                found: floor.data.len(),
+            });
+        }
+        for (j, row) in floor.data.iter().enumerate() {
+            if row.len() != width {
+                return Err(MapLoadError::RaggedFloor {
+                    path: format!("floors[{}].data[{}]", index, j),
+                    expected: width,
+                    found: row.len(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
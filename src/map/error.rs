@@ -0,0 +1,111 @@
+use std::fmt;
+
+// line and column in a script or data file, both starting at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourcePosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourcePosition {
+    pub(super) fn from_rhai(position: rhai::Position) -> Option<Self> {
+        Some(Self {
+            line: position.line()?,
+            column: position.position().unwrap_or(1),
+        })
+    }
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+// errors raised while turning a map script result into a `Map`
+#[derive(Debug, Clone, PartialEq)]
+pub enum MapLoadError {
+    // the script does not produce the map a level refers to
+    MissingFunction {
+        function: String,
+    },
+    // the script could not be read or compiled
+    Parse {
+        message: String,
+        position: Option<SourcePosition>,
+    },
+    // the script or one of its functions failed while running, or ran into
+    // one of the `ScriptLimits`
+    Script {
+        function: Option<String>,
+        message: String,
+        position: Option<SourcePosition>,
+    },
+    // the result does not match the map layout
+    Invalid {
+        path: String,
+        message: String,
+    },
+    // a floor does not have the same size as the first floor
+    RaggedFloor {
+        path: String,
+        expected: usize,
+        found: usize,
+    },
+    // the palette data file could not be read
+    Palette {
+        file: String,
+        message: String,
+    },
+}
+
+impl MapLoadError {
+    // where the error is in the file, if known
+    pub fn position(&self) -> Option<SourcePosition> {
+        match self {
+            MapLoadError::Parse { position, .. } | MapLoadError::Script { position, .. } => {
+                *position
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MapLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(position) = self.position() {
+            write!(f, "{}: ", position)?;
+        }
+        match self {
+            MapLoadError::MissingFunction { function } => {
+                write!(f, "function `{}` is not defined", function)
+            }
+            MapLoadError::Parse { message, .. } => write!(f, "{}", message),
+            MapLoadError::Script {
+                function: Some(function),
+                message,
+                ..
+            } => write!(f, "function `{}` failed: {}", function, message),
+            MapLoadError::Script {
+                function: None,
+                message,
+                ..
+            } => write!(f, "script failed: {}", message),
+            MapLoadError::Invalid { path, message } => write!(f, "{}: {}", path, message),
+            MapLoadError::RaggedFloor {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: expected {} entries, found {}",
+                path, expected, found
+            ),
+            MapLoadError::Palette { file, message } => {
+                write!(f, "palette `{}`: {}", file, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapLoadError {}
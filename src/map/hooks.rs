@@ -0,0 +1,431 @@
+use bevy::{asset::AssetServerSettings, asset::FileAssetIo, prelude::*};
+use bevy_rhai::StandardScope;
+use rhai::{
+    module_resolvers::FileModuleResolver, ASTFlags, CallFnOptions, Dynamic, Engine, EvalAltResult,
+    FuncArgs, NativeCallContext, Scope, Stmt, AST, FLOAT, INT,
+};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    asset::configure_engine, respawn_chunks, script::ScriptClock, Cell, ChangeLevel, LevelId,
+    LevelTransition, Map, MapAsset, MapChunk, MapLoadError, MapMaterials, MapQuery, RegionEntered,
+    RegionExited, ScriptLimits, SourcePosition, Visible,
+};
+use crate::{
+    audio::PlaySfx,
+    camera::{CameraRig, MovableCamera},
+    pathfinding::PathCache,
+};
+
+// functions of a map script called while its level is active, with `this`
+// bound to the `state` object map of the level's scope:
+//
+//     fn on_enter() { this.visits = (this.visits ?? 0) + 1; }
+//     fn on_exit() {}
+//     fn on_tick(dt) {}
+//     fn on_tile_entered(cell) { if cell.x == 2 && cell.z == 1 { set_tile(1, 2, 1, 0); } }
+//     fn on_region_entered(region) { if "exit" in region.tags { change_level("town"); } }
+//     fn on_region_exited(region) {}
+//
+// they can call `play_sfx(id)`, `play_sfx(id, position)`, `set_tile(floor, x, z, tile)`,
+// `focus_camera(position)` and `change_level(id)`. the top level of the
+// script does not run for them, so imports and constants go inside the hooks.
+const ON_ENTER: &str = "on_enter";
+const ON_EXIT: &str = "on_exit";
+const ON_TICK: &str = "on_tick";
+const ON_TILE_ENTERED: &str = "on_tile_entered";
+const ON_REGION_ENTERED: &str = "on_region_entered";
+const ON_REGION_EXITED: &str = "on_region_exited";
+
+// the variable of the level scope that keeps the state of its hooks
+const STATE: &str = "state";
+
+pub(super) fn is_hook(name: &str) -> bool {
+    [
+        ON_ENTER,
+        ON_EXIT,
+        ON_TICK,
+        ON_TILE_ENTERED,
+        ON_REGION_ENTERED,
+        ON_REGION_EXITED,
+    ]
+    .contains(&name)
+}
+
+// the functions of a script that defines at least one hook, rejected if its
+// top level imports or declares constants the hooks would not see
+pub(super) fn hooks_ast(ast: &AST) -> Result<Option<AST>, MapLoadError> {
+    if !ast.iter_functions().any(|function| is_hook(function.name)) {
+        return Ok(None);
+    }
+    for statement in ast.statements() {
+        let declared = match statement {
+            Stmt::Import(..) => "import",
+            Stmt::Var(_, flags, _) if flags.contains(ASTFlags::CONSTANT) => "constant",
+            _ => continue,
+        };
+        return Err(MapLoadError::Parse {
+            message: format!(
+                "hooks do not see a top-level {}, move it into the functions using it",
+                declared
+            ),
+            position: SourcePosition::from_rhai(statement.position()),
+        });
+    }
+    Ok(Some(ast.clone_functions_only()))
+}
+
+// an entity whose steps onto new cells call `on_tile_entered`
+#[derive(Component, Default)]
+pub struct TileWalker {
+    cell: Option<(LevelId, Cell)>,
+}
+
+// changes requested by the hooks, applied by `apply_host_commands`
+enum HostCommand {
+    PlaySfx(PlaySfx),
+    SetTile {
+        level: LevelId,
+        floor: usize,
+        cell: Cell,
+        tile: i32,
+    },
+    FocusCamera(Vec3),
+    ChangeLevel(LevelId),
+}
+
+#[derive(Default, Clone)]
+struct HostCommands(Arc<Mutex<Vec<HostCommand>>>);
+
+impl HostCommands {
+    fn push(&self, command: HostCommand) {
+        self.0.lock().unwrap().push(command);
+    }
+
+    fn drain(&self) -> Vec<HostCommand> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+// the engine the hooks of every level run on
+pub(super) struct LevelScripts {
+    engine: Engine,
+    clock: ScriptClock,
+    commands: HostCommands,
+}
+
+impl FromWorld for LevelScripts {
+    fn from_world(world: &mut World) -> Self {
+        let limits = world
+            .get_resource_or_insert_with(ScriptLimits::default)
+            .clone();
+        let asset_folder = world
+            .get_resource::<AssetServerSettings>()
+            .map_or("assets".to_string(), |settings| {
+                settings.asset_folder.clone()
+            });
+        let scripts = FileAssetIo::get_root_path()
+            .join(asset_folder)
+            .join("scripts");
+
+        let mut engine = Engine::new_raw();
+        let clock = limits.apply_restartable(&mut engine);
+        configure_engine(&mut engine);
+        // modules are read again on every import, so an edit applies to the
+        // next hook call like it applies to the next load of the map
+        let mut resolver = FileModuleResolver::new_with_path(scripts);
+        resolver.enable_cache(false);
+        engine.set_module_resolver(resolver);
+        let commands = HostCommands::default();
+        register_host_api(&mut engine, &commands);
+        Self {
+            engine,
+            clock,
+            commands,
+        }
+    }
+}
+
+impl LevelScripts {
+    // call a hook of a level if its script defines it, errors are logged
+    fn call(
+        &self,
+        level: &LevelId,
+        hooks: &AST,
+        scope: &mut StandardScope,
+        hook: &str,
+        args: impl FuncArgs,
+    ) {
+        if !hooks.iter_functions().any(|function| function.name == hook) {
+            return;
+        }
+        if !scope.contains(STATE) {
+            scope.push(STATE, rhai::Map::new());
+        }
+        let state = match scope.get_mut(STATE) {
+            Some(state) => state,
+            None => {
+                error!("`{}` of level `{}` is read-only", STATE, level);
+                return;
+            }
+        };
+        self.clock.restart();
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(state)
+            .with_tag(level.to_string());
+        // script functions do not see the variables of a scope, only `this`
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut Scope::new(),
+            hooks,
+            hook,
+            args,
+        );
+        if let Err(error) = result {
+            error!("`{}` of level `{}` failed: {}", hook, level, error);
+        }
+    }
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// the level whose hook is running
+fn calling_level(context: &NativeCallContext) -> LevelId {
+    let tag = context.tag().map(Dynamic::to_string).unwrap_or_default();
+    LevelId::from(tag.as_str())
+}
+
+fn register_host_api(engine: &mut Engine, commands: &HostCommands) {
+    let host = commands.clone();
+    engine.register_fn("play_sfx", move |id: &str| {
+        host.push(HostCommand::PlaySfx(PlaySfx::new(id)));
+    });
+    let host = commands.clone();
+    engine.register_fn("play_sfx", move |id: &str, position: Vec3| {
+        host.push(HostCommand::PlaySfx(PlaySfx::new(id).at(position)));
+    });
+    let host = commands.clone();
+    engine.register_fn(
+        "set_tile",
+        move |context: NativeCallContext,
+              floor: INT,
+              x: INT,
+              z: INT,
+              tile: INT|
+              -> ScriptResult<()> {
+            if floor < 0 || x < 0 || z < 0 {
+                return Err(format!("no tile at floor {}, x {}, z {}", floor, x, z).into());
+            }
+            host.push(HostCommand::SetTile {
+                level: calling_level(&context),
+                floor: floor as usize,
+                cell: Cell::new(x as usize, z as usize),
+                tile,
+            });
+            Ok(())
+        },
+    );
+    let host = commands.clone();
+    engine.register_fn("focus_camera", move |position: Vec3| {
+        host.push(HostCommand::FocusCamera(position));
+    });
+    let host = commands.clone();
+    engine.register_fn("change_level", move |level: &str| {
+        host.push(HostCommand::ChangeLevel(LevelId::from(level)));
+    });
+}
+
+// the level entities with the scope their hooks run against
+type HookLevels<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static LevelId,
+        &'static Handle<MapAsset>,
+        &'static mut StandardScope,
+    ),
+>;
+
+// call a hook of the current script of a level
+fn call_hook(
+    scripts: &LevelScripts,
+    map_assets: &Assets<MapAsset>,
+    levels: &mut HookLevels,
+    level: &LevelId,
+    hook: &str,
+    args: Vec<Dynamic>,
+) {
+    let found = levels.iter_mut().find(|(id, ..)| *id == level);
+    if let Some((level, handle, mut scope)) = found {
+        let hooks = map_assets
+            .get(handle)
+            .and_then(|asset| asset.hooks.as_ref());
+        if let Some(hooks) = hooks {
+            scripts.call(level, hooks, &mut scope, hook, args);
+        }
+    }
+}
+
+// on_exit and on_enter when the active levels change, then on_tile_entered
+// and on_tick
+pub(super) fn run_level_hooks(
+    time: Res<Time>,
+    scripts: Res<LevelScripts>,
+    transition: Res<LevelTransition>,
+    map_assets: Res<Assets<MapAsset>>,
+    maps: MapQuery,
+    mut levels: HookLevels,
+    mut walkers: Query<(&GlobalTransform, &mut TileWalker)>,
+    mut active: Local<Vec<LevelId>>,
+) {
+    let mut call = |level: &LevelId, hook: &str, args: Vec<Dynamic>| {
+        call_hook(&scripts, &map_assets, &mut levels, level, hook, args);
+    };
+
+    if transition.is_changed() {
+        for level in active.iter() {
+            if !transition.active().contains(level) {
+                call(level, ON_EXIT, Vec::new());
+            }
+        }
+        for level in transition.active() {
+            if !active.contains(level) {
+                call(level, ON_ENTER, Vec::new());
+            }
+        }
+        *active = transition.active().to_vec();
+    }
+
+    for (transform, mut walker) in walkers.iter_mut() {
+        let position = transform.translation;
+        let cell = maps.level_at(position).and_then(|level| {
+            let cell = maps.world_to_grid(level, position)?;
+            Some((level.clone(), cell))
+        });
+        if cell == walker.cell {
+            continue;
+        }
+        if let Some((level, cell)) = &cell {
+            let mut argument = rhai::Map::new();
+            argument.insert("x".into(), (cell.i as INT).into());
+            argument.insert("z".into(), (cell.j as INT).into());
+            call(level, ON_TILE_ENTERED, vec![argument.into()]);
+        }
+        walker.cell = cell;
+    }
+
+    let dt = time.delta_seconds() as FLOAT;
+    for level in transition.active() {
+        call(level, ON_TICK, vec![dt.into()]);
+    }
+}
+
+// on_region_entered and on_region_exited of the level owning the region
+pub(super) fn run_region_hooks(
+    scripts: Res<LevelScripts>,
+    map_assets: Res<Assets<MapAsset>>,
+    mut levels: HookLevels,
+    mut entered: EventReader<RegionEntered>,
+    mut exited: EventReader<RegionExited>,
+) {
+    let region = |name: &str, tags: &[String]| {
+        let mut region = rhai::Map::new();
+        region.insert("name".into(), name.into());
+        let tags: rhai::Array = tags.iter().map(|tag| tag.as_str().into()).collect();
+        region.insert("tags".into(), Dynamic::from_array(tags));
+        Dynamic::from_map(region)
+    };
+    for event in exited.iter() {
+        let args = vec![region(&event.region, &event.tags)];
+        call_hook(
+            &scripts,
+            &map_assets,
+            &mut levels,
+            &event.level,
+            ON_REGION_EXITED,
+            args,
+        );
+    }
+    for event in entered.iter() {
+        let args = vec![region(&event.region, &event.tags)];
+        call_hook(
+            &scripts,
+            &map_assets,
+            &mut levels,
+            &event.level,
+            ON_REGION_ENTERED,
+            args,
+        );
+    }
+}
+
+// carry out what the hooks asked for this frame
+#[allow(clippy::too_many_arguments)]
+pub(super) fn apply_host_commands(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    materials: Res<MapMaterials>,
+    scripts: Res<LevelScripts>,
+    mut paths: Option<ResMut<PathCache>>,
+    mut levels: Query<(&LevelId, &mut Map, &Visible)>,
+    chunks: Query<(Entity, &LevelId, &MapChunk)>,
+    mut cameras: Query<&mut CameraRig, With<MovableCamera>>,
+    mut sfx: EventWriter<PlaySfx>,
+    mut changes: EventWriter<ChangeLevel>,
+) {
+    let mut edited: Vec<LevelId> = Vec::new();
+    for command in scripts.commands.drain() {
+        match command {
+            HostCommand::PlaySfx(play) => sfx.send(play),
+            HostCommand::SetTile {
+                level,
+                floor,
+                cell,
+                tile,
+            } => {
+                let found = levels.iter_mut().find(|(id, ..)| **id == level);
+                let target = found.and_then(|(_, map, _)| {
+                    let row = map
+                        .into_inner()
+                        .floors
+                        .get_mut(floor)?
+                        .data
+                        .get_mut(cell.j)?;
+                    row.get_mut(cell.i)
+                });
+                match target {
+                    Some(target) => {
+                        *target = tile;
+                        if !edited.contains(&level) {
+                            edited.push(level);
+                        }
+                    }
+                    None => warn!(
+                        "level `{}` has no tile at floor {}, x {}, z {}",
+                        level, floor, cell.i, cell.j
+                    ),
+                }
+            }
+            HostCommand::FocusCamera(position) => {
+                for mut rig in cameras.iter_mut() {
+                    rig.focus = position;
+                }
+            }
+            HostCommand::ChangeLevel(level) => changes.send(ChangeLevel(level)),
+        }
+    }
+
+    // tiles do not move stairs, walls or ambient sounds, only chunks change
+    for (level, map, visible) in levels.iter() {
+        if !edited.contains(level) {
+            continue;
+        }
+        if let Some(paths) = paths.as_mut() {
+            paths.invalidate(level);
+        }
+        if visible.0 {
+            respawn_chunks(&mut commands, &mut meshes, &materials, level, map, &chunks);
+        }
+    }
+}
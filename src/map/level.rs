@@ -0,0 +1,192 @@
+use bevy::{
+    asset::{
+        AssetLoader, AssetServerSettings, BoxedFuture, FileAssetIo, LoadContext, LoadState,
+        LoadedAsset,
+    },
+    prelude::*,
+    reflect::TypeUuid,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt, path::Path};
+
+// identifies a level, for example `test_map`
+#[derive(Component, Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(transparent)]
+pub struct LevelId(pub String);
+
+impl fmt::Display for LevelId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<&str> for LevelId {
+    fn from(id: &str) -> Self {
+        LevelId(id.to_string())
+    }
+}
+
+// a level entry of `manifest.levels.ron`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LevelInfo {
+    pub id: LevelId,
+    #[serde(default)]
+    pub name: String,
+    // map asset path relative to the asset folder
+    pub script: String,
+    // function of a `.map.rhai` script producing the map
+    #[serde(default)]
+    pub entry: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+impl LevelInfo {
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.id.0
+        } else {
+            &self.name
+        }
+    }
+
+    // path of the map asset, labeled with the entry function
+    pub fn asset_path(&self) -> String {
+        match &self.entry {
+            Some(entry) => format!("{}#{}", self.script, entry),
+            None => self.script.clone(),
+        }
+    }
+}
+
+// contents of `manifest.levels.ron`
+#[derive(Debug, Clone, Deserialize, TypeUuid)]
+#[uuid = "722e5d07-f093-4db3-a881-135bf9d6eeb6"]
+#[serde(deny_unknown_fields)]
+pub struct LevelManifest {
+    #[serde(default)]
+    pub start: Option<LevelId>,
+    pub levels: Vec<LevelInfo>,
+}
+
+#[derive(Default)]
+pub(super) struct LevelManifestLoader;
+
+impl AssetLoader for LevelManifestLoader {
+    fn extensions(&self) -> &[&str] {
+        &["levels.ron"]
+    }
+
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let manifest = ron::de::from_bytes::<LevelManifest>(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(manifest));
+            Ok(())
+        })
+    }
+}
+
+// every level the game knows about
+#[derive(Debug, Default)]
+pub struct LevelRegistry {
+    start: Option<LevelId>,
+    levels: Vec<LevelInfo>,
+}
+
+impl LevelRegistry {
+    pub fn get(&self, id: &LevelId) -> Option<&LevelInfo> {
+        self.levels.iter().find(|info| info.id == *id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LevelInfo> {
+        self.levels.iter()
+    }
+
+    // the level declared as `start`, or the first one
+    pub fn start_level(&self) -> Option<&LevelId> {
+        self.start
+            .as_ref()
+            .or_else(|| self.levels.first().map(|info| &info.id))
+    }
+
+    fn from_manifest(manifest: &LevelManifest) -> Self {
+        Self {
+            start: manifest.start.clone(),
+            levels: manifest.levels.clone(),
+        }
+    }
+
+    // one level per `.map.rhai` or `.map.ron` file in `scripts/`
+    fn scan(asset_folder: &Path) -> Self {
+        let mut levels = Vec::new();
+        if let Ok(entries) = std::fs::read_dir(asset_folder.join("scripts")) {
+            for entry in entries.flatten() {
+                let file_name = entry.file_name().to_string_lossy().to_string();
+                let id = match file_name
+                    .strip_suffix(".map.rhai")
+                    .or_else(|| file_name.strip_suffix(".map.ron"))
+                {
+                    Some(id) => id.to_string(),
+                    None => continue,
+                };
+                levels.push(LevelInfo {
+                    id: LevelId(id),
+                    name: String::new(),
+                    script: format!("scripts/{}", file_name),
+                    entry: None,
+                    metadata: HashMap::new(),
+                });
+            }
+        }
+        levels.sort_by(|a, b| a.id.0.cmp(&b.id.0));
+        Self {
+            start: None,
+            levels,
+        }
+    }
+}
+
+pub(super) struct LevelManifestHandle(pub(super) Handle<LevelManifest>);
+
+// rebuild the registry whenever `manifest.levels.ron` changes, or scan `scripts/`
+// once if there is no manifest
+pub(super) fn build_level_registry(
+    mut registry: ResMut<LevelRegistry>,
+    mut events: EventReader<AssetEvent<LevelManifest>>,
+    mut scanned: Local<bool>,
+    asset_server: Res<AssetServer>,
+    settings: Option<Res<AssetServerSettings>>,
+    manifest_handle: Res<LevelManifestHandle>,
+    manifests: Res<Assets<LevelManifest>>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle }
+                if *handle == manifest_handle.0 =>
+            {
+                if let Some(manifest) = manifests.get(handle) {
+                    *registry = LevelRegistry::from_manifest(manifest);
+                    info!(
+                        "registered {} levels from manifest.levels.ron",
+                        registry.levels.len()
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !*scanned && asset_server.get_load_state(&manifest_handle.0) == LoadState::Failed {
+        *scanned = true;
+        let asset_folder = settings.map_or("assets".to_string(), |settings| {
+            settings.asset_folder.clone()
+        });
+        *registry = LevelRegistry::scan(&FileAssetIo::get_root_path().join(asset_folder));
+        info!("registered {} levels from scripts/", registry.levels.len());
+    }
+}
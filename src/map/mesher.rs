@@ -0,0 +1,415 @@
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use super::{Cell, Map};
+
+// a part of the floors of a level, built into one mesh per tile material
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapChunk {
+    pub coord: UVec2,
+    // hash of the geometry, used to skip chunks that did not change
+    pub hash: u64,
+}
+
+// materials and meshes shared by every level
+pub struct MapMaterials {
+    pub tile: Handle<StandardMaterial>,
+    pub dark_wall: Handle<StandardMaterial>,
+    pub cube: Handle<Mesh>,
+    pub quad: Handle<Mesh>,
+}
+
+impl FromWorld for MapMaterials {
+    fn from_world(world: &mut World) -> Self {
+        let mut materials = world
+            .get_resource_mut::<Assets<StandardMaterial>>()
+            .unwrap();
+        let tile = materials.add(Color::rgb(230. / 255., 230. / 255., 230. / 255.).into());
+        let dark_wall = materials.add(Color::rgb(0.0, 0.0, 0.0).into());
+        let mut meshes = world.get_resource_mut::<Assets<Mesh>>().unwrap();
+        let cube = meshes.add(Mesh::from(shape::Cube { size: 1.0 }));
+        let quad = meshes.add(Mesh::from(shape::Quad {
+            size: Vec2::ONE,
+            flip: false,
+        }));
+        Self {
+            tile,
+            dark_wall,
+            cube,
+            quad,
+        }
+    }
+}
+
+// vertices of one chunk, turned into meshes only when the chunk changed
+#[derive(Debug, Default)]
+pub(super) struct ChunkGeometry {
+    pub(super) coord: UVec2,
+    // one part per tile kind of the palette, `None` for the default material
+    pub(super) parts: Vec<(Option<usize>, MeshData)>,
+}
+
+impl ChunkGeometry {
+    pub(super) fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for (slot, data) in &self.parts {
+            slot.hash(&mut hasher);
+            data.hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    fn part(&mut self, slot: Option<usize>) -> &mut MeshData {
+        let index = match self.parts.iter().position(|(part, _)| *part == slot) {
+            Some(index) => index,
+            None => {
+                self.parts.push((slot, MeshData::default()));
+                self.parts.len() - 1
+            }
+        };
+        &mut self.parts[index].1
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct MeshData {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    uvs: Vec<[f32; 2]>,
+    indices: Vec<u32>,
+}
+
+impl MeshData {
+    fn hash<H: Hasher>(&self, hasher: &mut H) {
+        for position in &self.positions {
+            for value in position {
+                value.to_bits().hash(hasher);
+            }
+        }
+        for uv in &self.uvs {
+            for value in uv {
+                value.to_bits().hash(hasher);
+            }
+        }
+        self.indices.hash(hasher);
+    }
+
+    pub(super) fn to_mesh(&self) -> Mesh {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.set_attribute(Mesh::ATTRIBUTE_POSITION, self.positions.clone());
+        mesh.set_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals.clone());
+        mesh.set_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs.clone());
+        mesh.set_indices(Some(Indices::U32(self.indices.clone())));
+        mesh
+    }
+
+    // `u` and `v` span the face, counter-clockwise seen from the outside
+    fn push_quad(&mut self, origin: Vec3, u: Vec3, v: Vec3, (min, max): (Vec2, Vec2)) {
+        let normal = u.cross(v).normalize();
+        let start = self.positions.len() as u32;
+        let corners = [origin, origin + u, origin + u + v, origin + v];
+        let uvs = [
+            [min.x, max.y],
+            [max.x, max.y],
+            [max.x, min.y],
+            [min.x, min.y],
+        ];
+        for (corner, uv) in corners.iter().zip(uvs) {
+            self.positions.push(corner.to_array());
+            self.normals.push(normal.to_array());
+            self.uvs.push(uv);
+        }
+        self.indices
+            .extend([start, start + 1, start + 2, start, start + 2, start + 3]);
+    }
+}
+
+// builds the floors of a map into chunks of `chunk_size` x `chunk_size` cells
+//
+// every cell is a stack of solid spans: the base slab under the map and one
+// column per floor. overlapping spans of the same tile kind are merged and the
+// faces hidden by other spans are left out.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct MapMesher {
+    pub(super) chunk_size: usize,
+}
+
+impl Default for MapMesher {
+    fn default() -> Self {
+        Self { chunk_size: 16 }
+    }
+}
+
+impl MapMesher {
+    pub(super) fn build(&self, map: &Map) -> Vec<ChunkGeometry> {
+        if !map.is_loaded() {
+            return Vec::new();
+        }
+        let (width, depth) = (map.width(), map.depth());
+        let spans = CellSpans::new(map);
+
+        let chunk_size = self.chunk_size.max(1);
+        let mut chunks = Vec::new();
+        for cj in 0..(depth + chunk_size - 1) / chunk_size {
+            for ci in 0..(width + chunk_size - 1) / chunk_size {
+                let mut geometry = ChunkGeometry {
+                    coord: UVec2::new(ci as u32, cj as u32),
+                    ..default()
+                };
+                for j in cj * chunk_size..((cj + 1) * chunk_size).min(depth) {
+                    for i in ci * chunk_size..((ci + 1) * chunk_size).min(width) {
+                        let Vec2 { x, y: z } = map.cell_min(Cell { i, j });
+                        // x grows as i shrinks, z grows as j shrinks
+                        let sides = [
+                            (spans.solid(i.checked_sub(1), Some(j)), Side::PX),
+                            (spans.solid(Some(i + 1), Some(j)), Side::MX),
+                            (spans.solid(Some(i), j.checked_sub(1)), Side::PZ),
+                            (spans.solid(Some(i), Some(j + 1)), Side::MZ),
+                        ];
+                        let cell = spans.kinds(i, j);
+                        for span in cell {
+                            let uv = map.palette.uv_rect(span.slot);
+                            let data = geometry.part(span.slot);
+                            if !span.top_covered(cell) {
+                                let origin = Vec3::new(x, span.top, z);
+                                data.push_quad(origin, Vec3::Z, Vec3::X, uv);
+                            }
+                            if !span.bottom_covered(cell) {
+                                let origin = Vec3::new(x, span.bottom, z);
+                                data.push_quad(origin, Vec3::X, Vec3::Z, uv);
+                            }
+                            for (neighbour, side) in &sides {
+                                for (a, b) in uncovered((span.bottom, span.top), neighbour) {
+                                    side.push_face(data, Vec3::new(x, a, z), b - a, uv);
+                                }
+                            }
+                        }
+                    }
+                }
+                if !geometry.parts.is_empty() {
+                    chunks.push(geometry);
+                }
+            }
+        }
+        chunks
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    PX,
+    MX,
+    PZ,
+    MZ,
+}
+
+impl Side {
+    // side face of the cell whose lowest corner is `origin`
+    fn push_face(self, data: &mut MeshData, origin: Vec3, height: f32, uv: (Vec2, Vec2)) {
+        let height = Vec3::new(0.0, height, 0.0);
+        match self {
+            Side::PX => data.push_quad(origin + Vec3::X, height, Vec3::Z, uv),
+            Side::MX => data.push_quad(origin, Vec3::Z, height, uv),
+            Side::PZ => data.push_quad(origin + Vec3::Z, Vec3::X, height, uv),
+            Side::MZ => data.push_quad(origin, height, Vec3::X, uv),
+        }
+    }
+}
+
+// solid part of a cell made of a single tile kind
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    bottom: f32,
+    top: f32,
+    slot: Option<usize>,
+}
+
+impl Span {
+    // the top face touches a span of another tile kind
+    fn top_covered(&self, cell: &[Span]) -> bool {
+        cell.iter().any(|other| {
+            other.slot != self.slot && other.bottom <= self.top && self.top < other.top
+        })
+    }
+
+    fn bottom_covered(&self, cell: &[Span]) -> bool {
+        cell.iter().any(|other| {
+            other.slot != self.slot && other.bottom < self.bottom && self.bottom <= other.top
+        })
+    }
+}
+
+// spans of every cell, indexed by `j * width + i`
+struct CellSpans {
+    width: usize,
+    depth: usize,
+    kinds: Vec<Vec<Span>>,
+    // union of the spans of a cell regardless of the tile kind
+    solid: Vec<Vec<(f32, f32)>>,
+}
+
+impl CellSpans {
+    fn new(map: &Map) -> Self {
+        let (width, depth) = (map.width(), map.depth());
+        let mut kinds = vec![Vec::new(); width * depth];
+        for floor in &map.floors {
+            let floor_height = floor.height as f32 + map.position.y;
+            for (j, row) in floor.data.iter().enumerate() {
+                for (i, &tile) in row.iter().enumerate() {
+                    let height = match map.palette.height(tile) {
+                        Some(height) => height,
+                        None => continue,
+                    };
+                    let slot = map.palette.slot(tile);
+                    let cell = &mut kinds[j * width + i];
+                    push_span(cell, -0.5, 0.0, slot);
+                    if height > 0 {
+                        push_span(cell, floor_height, floor_height + height as f32, slot);
+                    }
+                }
+            }
+        }
+        let mut solid = Vec::with_capacity(kinds.len());
+        for cell in &mut kinds {
+            merge_kinds(cell);
+            let mut spans = cell.iter().map(|span| (span.bottom, span.top)).collect();
+            merge(&mut spans);
+            solid.push(spans);
+        }
+        Self {
+            width,
+            depth,
+            kinds,
+            solid,
+        }
+    }
+
+    fn kinds(&self, i: usize, j: usize) -> &[Span] {
+        &self.kinds[j * self.width + i]
+    }
+
+    // cells outside of the map are empty
+    fn solid(&self, i: Option<usize>, j: Option<usize>) -> &[(f32, f32)] {
+        match (i, j) {
+            (Some(i), Some(j)) if i < self.width && j < self.depth => {
+                &self.solid[j * self.width + i]
+            }
+            _ => &[],
+        }
+    }
+}
+
+// later floors replace the spans of other tile kinds they fully cover, like
+// the shared base slab
+fn push_span(cell: &mut Vec<Span>, bottom: f32, top: f32, slot: Option<usize>) {
+    cell.retain(|span| span.slot == slot || span.bottom < bottom || span.top > top);
+    cell.push(Span { bottom, top, slot });
+}
+
+// join the spans of the same tile kind that overlap or touch
+fn merge_kinds(cell: &mut Vec<Span>) {
+    cell.sort_by(|a, b| a.slot.cmp(&b.slot).then(a.bottom.total_cmp(&b.bottom)));
+    let mut merged: Vec<Span> = Vec::with_capacity(cell.len());
+    for &span in cell.iter() {
+        match merged.last_mut() {
+            Some(last) if last.slot == span.slot && span.bottom <= last.top => {
+                last.top = last.top.max(span.top)
+            }
+            _ => merged.push(span),
+        }
+    }
+    merged.sort_by(|a, b| a.bottom.total_cmp(&b.bottom));
+    *cell = merged;
+}
+
+// sort the spans and join the ones that overlap or touch
+fn merge(spans: &mut Vec<(f32, f32)>) {
+    spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut merged: Vec<(f32, f32)> = Vec::with_capacity(spans.len());
+    for &(bottom, top) in spans.iter() {
+        match merged.last_mut() {
+            Some(last) if bottom <= last.1 => last.1 = last.1.max(top),
+            _ => merged.push((bottom, top)),
+        }
+    }
+    *spans = merged;
+}
+
+// parts of `span` not covered by the merged spans of a neighbour
+fn uncovered((bottom, top): (f32, f32), neighbour: &[(f32, f32)]) -> Vec<(f32, f32)> {
+    let mut visible = Vec::new();
+    let mut start = bottom;
+    for &(a, b) in neighbour {
+        if b <= start {
+            continue;
+        }
+        if a >= top {
+            break;
+        }
+        if a > start {
+            visible.push((start, a));
+        }
+        start = b;
+        if start >= top {
+            break;
+        }
+    }
+    if start < top {
+        visible.push((start, top));
+    }
+    visible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapAsset;
+
+    fn build(script: &str, chunk_size: usize) -> Vec<ChunkGeometry> {
+        let asset = MapAsset::from_script(script, None).unwrap();
+        MapMesher { chunk_size }.build(&asset.map)
+    }
+
+    // corners of every quad of a chunk
+    fn quads(geometry: &ChunkGeometry) -> Vec<Vec<[f32; 3]>> {
+        let parts = geometry.parts.iter();
+        parts
+            .flat_map(|(_, data)| data.positions.chunks(4).map(<[_]>::to_vec))
+            .collect()
+    }
+
+    #[test]
+    fn same_kind_neighbours_share_no_faces() {
+        // cell 0 spans x 1..2 and cell 1 spans x 0..1
+        let chunks = build("#{ floors: [#{ data: [[1, 1]] }] }", 16);
+        assert_eq!(chunks.len(), 1);
+        let quads = quads(&chunks[0]);
+        let inner = quads
+            .iter()
+            .filter(|quad| quad.iter().all(|corner| corner[0] == 1.0));
+        assert_eq!(inner.count(), 0);
+        // a top, a bottom and three outer sides per cell
+        assert_eq!(quads.len(), 10);
+    }
+
+    #[test]
+    fn tile_edit_changes_only_its_chunk() {
+        let before = build("#{ floors: [#{ data: [[0, 0, 0, 0]] }] }", 2);
+        let after = build("#{ floors: [#{ data: [[0, 0, 0, 1]] }] }", 2);
+        assert_eq!(before.len(), 2);
+        assert_eq!(after.len(), 2);
+        let hash = |chunks: &[ChunkGeometry], coord: UVec2| {
+            let chunk = chunks.iter().find(|chunk| chunk.coord == coord).unwrap();
+            chunk.hash()
+        };
+        let (first, second) = (UVec2::new(0, 0), UVec2::new(1, 0));
+        assert_eq!(hash(&before, first), hash(&after, first));
+        assert_ne!(hash(&before, second), hash(&after, second));
+    }
+}
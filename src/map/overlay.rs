@@ -0,0 +1,69 @@
+use bevy::prelude::*;
+use bevy_inspector_egui::{bevy_egui::EguiContext, egui};
+
+use super::{LevelId, MapAsset, MapLoadFailed};
+
+// the last load error of every level, until its map loads again
+pub struct MapErrors {
+    // draw the errors on screen, they are logged either way
+    pub show_overlay: bool,
+    errors: Vec<MapLoadFailed>,
+}
+
+impl Default for MapErrors {
+    fn default() -> Self {
+        Self {
+            show_overlay: true,
+            errors: Vec::new(),
+        }
+    }
+}
+
+impl MapErrors {
+    pub fn iter(&self) -> impl Iterator<Item = &MapLoadFailed> {
+        self.errors.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+pub(super) fn collect_map_errors(
+    mut errors: ResMut<MapErrors>,
+    mut failures: EventReader<MapLoadFailed>,
+    mut events: EventReader<AssetEvent<MapAsset>>,
+    levels: Query<(&LevelId, &Handle<MapAsset>)>,
+) {
+    for event in events.iter() {
+        let loaded = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        for (level, handle) in levels.iter() {
+            if handle == loaded {
+                errors.errors.retain(|failure| failure.level != *level);
+            }
+        }
+    }
+    for failure in failures.iter() {
+        errors.errors.retain(|old| old.level != failure.level);
+        errors.errors.push(failure.clone());
+    }
+}
+
+pub(super) fn show_map_errors(errors: Res<MapErrors>, egui_context: Option<ResMut<EguiContext>>) {
+    let mut egui_context = match egui_context {
+        Some(egui_context) if errors.show_overlay && !errors.is_empty() => egui_context,
+        _ => return,
+    };
+    egui::Window::new("Map errors")
+        .anchor(egui::Align2::LEFT_BOTTOM, [8.0, -8.0])
+        .resizable(false)
+        .show(egui_context.ctx_mut(), |ui| {
+            for failure in errors.iter() {
+                ui.label(format!("{} ({})", failure.path, failure.level));
+                ui.colored_label(egui::Color32::LIGHT_RED, failure.error.to_string());
+            }
+        });
+}
@@ -0,0 +1,123 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+// properties of the tiles using `id` in `Floor.data`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileKind {
+    pub id: i32,
+    // free form name, for example "grass" or "water"
+    #[serde(default)]
+    pub tag: String,
+    // height of the column above the floor, 0 is base only
+    #[serde(default)]
+    pub height: i32,
+    #[serde(default = "TileKind::default_color")]
+    pub color: [f32; 3],
+    // index of the texture in the palette atlas
+    #[serde(default)]
+    pub texture: Option<u32>,
+    #[serde(default = "TileKind::default_walkable")]
+    pub walkable: bool,
+}
+
+impl TileKind {
+    fn default_color() -> [f32; 3] {
+        [230. / 255., 230. / 255., 230. / 255.]
+    }
+
+    fn default_walkable() -> bool {
+        true
+    }
+}
+
+// tile kinds of a map, declared in the `palette` field of a map or in a
+// `.palette.ron` data file referred to by `file`
+//
+// ids missing from the palette keep their legacy meaning: -1 is no tile, 0 is
+// base only and any other value is the height of the column.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TilePalette {
+    // data file whose tiles come before the inline ones, relative to the asset folder
+    pub file: Option<String>,
+    // texture atlas image, relative to the asset folder
+    pub atlas: Option<String>,
+    // columns and rows of the atlas
+    pub atlas_size: UVec2,
+    pub tiles: Vec<TileKind>,
+    // one material per tile kind, created by the map loader
+    #[serde(skip)]
+    pub(super) materials: Vec<Handle<StandardMaterial>>,
+}
+
+impl Default for TilePalette {
+    fn default() -> Self {
+        Self {
+            file: None,
+            atlas: None,
+            atlas_size: UVec2::ONE,
+            tiles: Vec::new(),
+            materials: Vec::new(),
+        }
+    }
+}
+
+impl TilePalette {
+    pub fn get(&self, id: i32) -> Option<&TileKind> {
+        self.tiles.iter().find(|kind| kind.id == id)
+    }
+
+    // height of the column of a tile, `None` if there is no tile
+    pub fn height(&self, id: i32) -> Option<i32> {
+        match self.get(id) {
+            Some(kind) => Some(kind.height),
+            None if id == -1 => None,
+            None => Some(id.max(0)),
+        }
+    }
+
+    pub fn is_walkable(&self, id: i32) -> bool {
+        match self.get(id) {
+            Some(kind) => kind.walkable,
+            None => id != -1,
+        }
+    }
+
+    pub fn tag(&self, id: i32) -> Option<&str> {
+        self.get(id).map(|kind| kind.tag.as_str())
+    }
+
+    // index of the tile kind, `None` for ids using the default material
+    pub(super) fn slot(&self, id: i32) -> Option<usize> {
+        self.tiles.iter().position(|kind| kind.id == id)
+    }
+
+    pub(super) fn material(&self, slot: Option<usize>) -> Option<&Handle<StandardMaterial>> {
+        slot.and_then(|slot| self.materials.get(slot))
+    }
+
+    // corners of the atlas texture of a tile kind in texture coordinates
+    pub(super) fn uv_rect(&self, slot: Option<usize>) -> (Vec2, Vec2) {
+        let index = match slot.and_then(|slot| self.tiles[slot].texture) {
+            Some(index) if self.atlas.is_some() => index,
+            _ => return (Vec2::ZERO, Vec2::ONE),
+        };
+        let size = self.atlas_size.max(UVec2::ONE);
+        let cell = Vec2::ONE / size.as_vec2();
+        let min = UVec2::new(index % size.x, index / size.x).as_vec2() * cell;
+        (min, min + cell)
+    }
+
+    // add the tiles of a palette data file, inline tiles take precedence
+    pub(super) fn merge_file(&mut self, file: TilePalette) {
+        if self.atlas.is_none() {
+            self.atlas = file.atlas;
+            self.atlas_size = file.atlas_size;
+        }
+        let mut tiles = file.tiles;
+        tiles.retain(|kind| self.get(kind.id).is_none());
+        tiles.append(&mut self.tiles);
+        self.tiles = tiles;
+    }
+}
@@ -0,0 +1,526 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use super::{Direction, LevelId, Map, Stair, Visible, Wall};
+use crate::audio::{MapSfx, Playlist};
+
+// largest height difference crossed between two neighbouring cells, the
+// tiles are whole units high so only stairs bridge floors
+pub const MAX_STEP: f32 = 0.25;
+
+// column `i` and row `j` of `Floor.data`, the same on every floor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cell {
+    pub i: usize,
+    pub j: usize,
+}
+
+impl Cell {
+    pub fn new(i: usize, j: usize) -> Self {
+        Self { i, j }
+    }
+}
+
+impl Map {
+    // corner of a cell with the smallest x and z, `i` and `j` grow towards -x and -z
+    pub(super) fn cell_min(&self, cell: Cell) -> Vec2 {
+        Vec2::new(
+            (self.width() - 1 - cell.i) as f32 + self.position.x,
+            (self.depth() - 1 - cell.j) as f32 + self.position.z,
+        )
+    }
+
+    pub(super) fn contains(&self, cell: Cell) -> bool {
+        self.is_loaded() && cell.i < self.width() && cell.j < self.depth()
+    }
+
+    pub(super) fn world_to_grid(&self, world: Vec3) -> Option<Cell> {
+        if !self.is_loaded() {
+            return None;
+        }
+        let x = (world.x - self.position.x).floor();
+        let z = (world.z - self.position.z).floor();
+        if x < 0.0 || z < 0.0 || x >= self.width() as f32 || z >= self.depth() as f32 {
+            return None;
+        }
+        Some(Cell {
+            i: self.width() - 1 - x as usize,
+            j: self.depth() - 1 - z as usize,
+        })
+    }
+
+    // centre of the walkable surface of a cell
+    pub(super) fn grid_to_world(&self, cell: Cell) -> Vec3 {
+        let center = self.cell_min(cell) + Vec2::splat(0.5);
+        let y = self
+            .surface_height_at(Vec3::new(center.x, 0.0, center.y))
+            .unwrap_or(0.0);
+        Vec3::new(center.x, y, center.y)
+    }
+
+    pub(super) fn tile_at(&self, floor: usize, cell: Cell) -> Option<i32> {
+        let floor = self.floors.get(floor)?;
+        floor.data.get(cell.j)?.get(cell.i).copied()
+    }
+
+    // top of the tile of a floor, the base slab for tiles without a column
+    fn floor_top(&self, floor: usize, cell: Cell) -> Option<f32> {
+        let height = self.palette.height(self.tile_at(floor, cell)?)?;
+        if height > 0 {
+            Some(self.floors[floor].height as f32 + self.position.y + height as f32)
+        } else {
+            Some(0.0)
+        }
+    }
+
+    // floor whose tile is the top of a cell
+    pub(super) fn top_floor(&self, cell: Cell) -> Option<usize> {
+        let mut top: Option<(usize, f32)> = None;
+        for index in 0..self.floors.len() {
+            let height = match self.floor_top(index, cell) {
+                Some(height) => height,
+                None => continue,
+            };
+            if top.map_or(true, |(_, top)| height >= top) {
+                top = Some((index, height));
+            }
+        }
+        top.map(|(index, _)| index)
+    }
+
+    // top of the tiles of a cell, ignoring stairs
+    pub(super) fn cell_height(&self, cell: Cell) -> Option<f32> {
+        self.floor_top(self.top_floor(cell)?, cell)
+    }
+
+    pub(super) fn surface_height_at(&self, world: Vec3) -> Option<f32> {
+        let point = Vec2::new(world.x, world.z);
+        if let Some(stair) = self.stairs.iter().find(|stair| stair.contains(point)) {
+            return Some(stair.height_at(point));
+        }
+        self.cell_height(self.world_to_grid(world)?)
+    }
+
+    // top of the spawned geometry, the steps of a stair instead of its ramp
+    pub(super) fn geometry_height_at(&self, world: Vec3) -> Option<f32> {
+        let point = Vec2::new(world.x, world.z);
+        if let Some(stair) = self.stairs.iter().find(|stair| stair.contains(point)) {
+            return Some(stair.step_height_at(point));
+        }
+        self.cell_height(self.world_to_grid(world)?)
+    }
+
+    // index of the stair whose footprint contains the world position
+    pub(super) fn stair_at(&self, world: Vec3) -> Option<usize> {
+        let point = Vec2::new(world.x, world.z);
+        self.stairs.iter().position(|stair| stair.contains(point))
+    }
+
+    pub(super) fn is_walkable(&self, cell: Cell) -> bool {
+        let floor = match self.top_floor(cell) {
+            Some(floor) => floor,
+            None => return false,
+        };
+        self.tile_at(floor, cell)
+            .map_or(false, |id| self.palette.is_walkable(id))
+    }
+
+    // walkable cells next to `cell` that can be stepped onto, stairs act as
+    // ramps between the heights at both of their ends
+    pub(super) fn neighbors(&self, cell: Cell, max_step: f32) -> Vec<Cell> {
+        let mut neighbors = Vec::with_capacity(4);
+        if !self.is_walkable(cell) {
+            return neighbors;
+        }
+        let candidates = [
+            (cell.i.checked_sub(1), Some(cell.j)),
+            (cell.i.checked_add(1), Some(cell.j)),
+            (Some(cell.i), cell.j.checked_sub(1)),
+            (Some(cell.i), cell.j.checked_add(1)),
+        ];
+        for candidate in candidates {
+            let next = match candidate {
+                (Some(i), Some(j)) => Cell { i, j },
+                _ => continue,
+            };
+            if !self.contains(next) || !self.is_walkable(next) || self.wall_between(cell, next) {
+                continue;
+            }
+            let (from, to) = match (self.edge_height(cell, next), self.edge_height(next, cell)) {
+                (Some(from), Some(to)) => (from, to),
+                _ => continue,
+            };
+            if (from - to).abs() <= max_step {
+                neighbors.push(next);
+            }
+        }
+        neighbors
+    }
+
+    // height of `cell` at its edge shared with `towards`
+    fn edge_height(&self, cell: Cell, towards: Cell) -> Option<f32> {
+        let center = self.cell_min(cell) + Vec2::splat(0.5);
+        let edge = (center + self.cell_min(towards) + Vec2::splat(0.5)) / 2.0;
+        match self.stairs.iter().find(|stair| stair.contains(center)) {
+            Some(stair) => Some(stair.height_at(edge)),
+            None => self.cell_height(cell),
+        }
+    }
+
+    // a wall stands on the edge shared by two neighbouring cells
+    fn wall_between(&self, a: Cell, b: Cell) -> bool {
+        let center_a = self.cell_min(a) + Vec2::splat(0.5);
+        let center_b = self.cell_min(b) + Vec2::splat(0.5);
+        let edge = (center_a + center_b) / 2.0;
+        let along_x = center_a.y == center_b.y;
+        let (low, high) = match (self.cell_height(a), self.cell_height(b)) {
+            (Some(a), Some(b)) => (a.min(b), a.max(b)),
+            _ => return false,
+        };
+        self.walls
+            .iter()
+            .any(|wall| wall.blocks(edge, along_x) && wall.overlaps_height(low, high + 1.0))
+    }
+}
+
+impl Wall {
+    // walls facing x lie on a plane of constant x and span `size.x` along z,
+    // walls facing z lie on a plane of constant z and span `size.x` along x
+    fn blocks(&self, edge: Vec2, along_x: bool) -> bool {
+        let (plane, start, point) = match self.direction {
+            Direction::PX | Direction::MX if along_x => {
+                (self.translation.x, self.translation.z, edge)
+            }
+            Direction::PZ | Direction::MZ if !along_x => (
+                self.translation.z,
+                self.translation.x,
+                Vec2::new(edge.y, edge.x),
+            ),
+            _ => return false,
+        };
+        (point.x - plane).abs() < 0.1 && point.y > start && point.y < start + self.size.x
+    }
+
+    fn overlaps_height(&self, low: f32, high: f32) -> bool {
+        let bottom = self.translation.y - 0.5;
+        bottom < high && bottom + self.size.y > low
+    }
+}
+
+impl Stair {
+    // the footprint of a stair starts at its translation and spans its scale
+    fn contains(&self, point: Vec2) -> bool {
+        let min = Vec2::new(self.translation.x, self.translation.z);
+        let max = min + Vec2::new(self.scale.x, self.scale.z);
+        point.cmpge(min).all() && point.cmplt(max).all()
+    }
+
+    // height of the ramp going up along `direction`
+    fn height_at(&self, point: Vec2) -> f32 {
+        let local = point - Vec2::new(self.translation.x, self.translation.z);
+        let rise = match self.direction {
+            Direction::PX => local.x / self.scale.x,
+            Direction::MX => 1.0 - local.x / self.scale.x,
+            Direction::PZ => local.y / self.scale.z,
+            Direction::MZ => 1.0 - local.y / self.scale.z,
+        };
+        self.translation.y + rise.clamp(0.0, 1.0) * self.scale.y
+    }
+
+    // top of the step under `point`, `spawn_props` builds three steps per
+    // tile of length and each ends at the ramp height of its far side
+    fn step_height_at(&self, point: Vec2) -> f32 {
+        let local = point - Vec2::new(self.translation.x, self.translation.z);
+        let (along, length) = match self.direction {
+            Direction::PX | Direction::MX => (local.x, self.scale.x),
+            Direction::PZ | Direction::MZ => (local.y, self.scale.z),
+        };
+        let steps = (3.0 * length).floor().max(1.0);
+        let index = (along * 3.0).floor().clamp(0.0, steps - 1.0) + 1.0;
+        let step = match self.direction {
+            Direction::PX | Direction::PZ => index,
+            Direction::MX | Direction::MZ => steps - index + 1.0,
+        };
+        self.translation.y + step / 3.0 * self.scale.y / length
+    }
+}
+
+// read only view of the tiles of a map, usable without a `World`
+#[derive(Clone, Copy)]
+pub struct MapGrid<'a> {
+    map: &'a Map,
+}
+
+impl<'a> MapGrid<'a> {
+    pub(super) fn new(map: &'a Map) -> Self {
+        Self { map }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.map.is_loaded() {
+            self.map.width()
+        } else {
+            0
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        if self.map.is_loaded() {
+            self.map.depth()
+        } else {
+            0
+        }
+    }
+
+    pub fn contains(&self, cell: Cell) -> bool {
+        self.map.contains(cell)
+    }
+
+    pub fn world_to_grid(&self, world: Vec3) -> Option<Cell> {
+        self.map.world_to_grid(world)
+    }
+
+    pub fn grid_to_world(&self, cell: Cell) -> Option<Vec3> {
+        self.contains(cell).then(|| self.map.grid_to_world(cell))
+    }
+
+    pub fn surface_height_at(&self, world: Vec3) -> Option<f32> {
+        self.map.surface_height_at(world)
+    }
+
+    // like `surface_height_at`, but stairs have the height of their steps
+    pub fn geometry_height_at(&self, world: Vec3) -> Option<f32> {
+        self.map.geometry_height_at(world)
+    }
+
+    pub fn tile_at(&self, floor: usize, cell: Cell) -> Option<i32> {
+        self.map.tile_at(floor, cell)
+    }
+
+    pub fn is_walkable(&self, cell: Cell) -> bool {
+        self.contains(cell) && self.map.is_walkable(cell)
+    }
+
+    // floor whose tile is the top of a cell
+    pub fn top_floor(&self, cell: Cell) -> Option<usize> {
+        self.map.top_floor(cell)
+    }
+
+    // corners of the grid on the ground, smallest x and z first
+    pub fn bounds(&self) -> (Vec2, Vec2) {
+        let min = Vec2::new(self.map.position.x, self.map.position.z);
+        (
+            min,
+            min + Vec2::new(self.width() as f32, self.depth() as f32),
+        )
+    }
+
+    // lowest and highest point of the tiles and stairs
+    pub fn height_range(&self) -> Option<(f32, f32)> {
+        if !self.map.is_loaded() {
+            return None;
+        }
+        let mut top = 0.0_f32;
+        for j in 0..self.depth() {
+            for i in 0..self.width() {
+                if let Some(height) = self.map.cell_height(Cell { i, j }) {
+                    top = top.max(height);
+                }
+            }
+        }
+        for stair in &self.map.stairs {
+            top = top.max(stair.translation.y + stair.scale.y);
+        }
+        Some((-0.5, top))
+    }
+
+    // cells reachable in one step, climbing at most `max_step`
+    pub fn neighbors(&self, cell: Cell, max_step: f32) -> Vec<Cell> {
+        if !self.contains(cell) {
+            return Vec::new();
+        }
+        self.map.neighbors(cell, max_step)
+    }
+}
+
+// read access to the tiles of the loaded levels
+#[derive(SystemParam)]
+pub struct MapQuery<'w, 's> {
+    levels: Query<'w, 's, (&'static LevelId, &'static Map, &'static Visible)>,
+}
+
+impl<'w, 's> MapQuery<'w, 's> {
+    fn map(&self, level: &LevelId) -> Option<&Map> {
+        self.levels
+            .iter()
+            .find(|(id, map, _)| *id == level && map.is_loaded())
+            .map(|(_, map, _)| map)
+    }
+
+    pub fn grid(&self, level: &LevelId) -> Option<MapGrid> {
+        self.map(level).map(MapGrid::new)
+    }
+
+    pub fn music(&self, level: &LevelId) -> Option<&Playlist> {
+        self.map(level)?.music.as_ref()
+    }
+
+    pub fn sfx(&self, level: &LevelId) -> Option<&MapSfx> {
+        self.map(level).map(|map| &map.sfx)
+    }
+
+    // visible level and index of the stair at the world position
+    pub fn stair_at(&self, world: Vec3) -> Option<(&LevelId, usize)> {
+        self.levels
+            .iter()
+            .filter(|(_, map, visible)| visible.0 && map.is_loaded())
+            .find_map(|(level, map, _)| Some((level, map.stair_at(world)?)))
+    }
+
+    pub fn visible_grids(&self) -> impl Iterator<Item = (&LevelId, MapGrid)> + '_ {
+        self.levels
+            .iter()
+            .filter(|(_, map, visible)| visible.0 && map.is_loaded())
+            .map(|(level, map, _)| (level, MapGrid::new(map)))
+    }
+
+    // bounding box on the ground of every visible level
+    pub fn bounds(&self) -> Option<(Vec2, Vec2)> {
+        self.visible_grids()
+            .map(|(_, grid)| grid.bounds())
+            .reduce(|(min, max), (grid_min, grid_max)| (min.min(grid_min), max.max(grid_max)))
+    }
+
+    // visible level whose grid contains the world position
+    pub fn level_at(&self, world: Vec3) -> Option<&LevelId> {
+        self.levels
+            .iter()
+            .filter(|(_, _, visible)| visible.0)
+            .find(|(_, map, _)| map.world_to_grid(world).is_some())
+            .map(|(level, ..)| level)
+    }
+
+    pub fn world_to_grid(&self, level: &LevelId, world: Vec3) -> Option<Cell> {
+        self.map(level)?.world_to_grid(world)
+    }
+
+    pub fn grid_to_world(&self, level: &LevelId, cell: Cell) -> Option<Vec3> {
+        let map = self.map(level)?;
+        map.contains(cell).then(|| map.grid_to_world(cell))
+    }
+
+    // height of the ground of the visible levels, stairs included
+    pub fn surface_height_at(&self, world: Vec3) -> Option<f32> {
+        self.levels
+            .iter()
+            .filter(|(_, _, visible)| visible.0)
+            .filter_map(|(_, map, _)| map.surface_height_at(world))
+            .reduce(f32::max)
+    }
+
+    pub fn tile_at(&self, level: &LevelId, floor: usize, cell: Cell) -> Option<i32> {
+        self.map(level)?.tile_at(floor, cell)
+    }
+
+    pub fn neighbors(&self, level: &LevelId, cell: Cell) -> Vec<Cell> {
+        self.grid(level)
+            .map_or_else(Vec::new, |grid| grid.neighbors(cell, MAX_STEP))
+    }
+
+    pub fn is_walkable(&self, level: &LevelId, cell: Cell) -> bool {
+        self.grid(level)
+            .map_or(false, |grid| grid.is_walkable(cell))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapAsset;
+
+    fn map(script: &str) -> Map {
+        MapAsset::from_script(script, None).unwrap().map
+    }
+
+    #[test]
+    fn grid_and_world_round_trip() {
+        let map = map(r#"
+            #{
+                position: [2.0, 0.0, -1.0],
+                floors: [#{ data: [[0, 1, 2], [2, 1, 0]] }],
+            }
+        "#);
+        let grid = MapGrid::new(&map);
+        for j in 0..grid.depth() {
+            for i in 0..grid.width() {
+                let cell = Cell::new(i, j);
+                let world = grid.grid_to_world(cell).unwrap();
+                assert_eq!(grid.world_to_grid(world), Some(cell));
+                assert_eq!(Some(world.y), map.cell_height(cell));
+            }
+        }
+        // i grows towards -x and j towards -z
+        assert_eq!(
+            grid.world_to_grid(Vec3::new(4.5, 0.0, 0.5)),
+            Some(Cell::new(0, 0))
+        );
+        assert_eq!(grid.world_to_grid(Vec3::new(1.5, 0.0, 0.5)), None);
+        assert_eq!(grid.world_to_grid(Vec3::new(2.5, 0.0, 1.5)), None);
+        assert_eq!(grid.grid_to_world(Cell::new(3, 0)), None);
+    }
+
+    #[test]
+    fn stairs_interpolate_height() {
+        // cell 1 spans x 1..2 and ramps up towards cell 2 at x 0..1
+        let map = map(r#"
+            #{
+                floors: [#{ data: [[0, 0, 1]] }],
+                stairs: [#{ translation: [1.0, 0.0, 0.0], direction: "MX" }],
+            }
+        "#);
+        let grid = MapGrid::new(&map);
+        let height = |x: f32| grid.surface_height_at(Vec3::new(x, 0.0, 0.5));
+        assert_eq!(height(2.5), Some(0.0));
+        assert_eq!(height(1.75), Some(0.25));
+        assert_eq!(height(1.25), Some(0.75));
+        assert_eq!(height(0.5), Some(1.0));
+        // the spawned steps are a third of a tile deep
+        let step = grid.geometry_height_at(Vec3::new(1.75, 0.0, 0.5)).unwrap();
+        assert!((step - 1.0 / 3.0).abs() < 1e-6);
+
+        let mut neighbors = grid.neighbors(Cell::new(1, 0), MAX_STEP);
+        neighbors.sort_by_key(|cell| cell.i);
+        assert_eq!(neighbors, vec![Cell::new(0, 0), Cell::new(2, 0)]);
+        // without the stair the step onto cell 2 is too high
+        assert_eq!(
+            grid.neighbors(Cell::new(2, 0), MAX_STEP),
+            vec![Cell::new(1, 0)]
+        );
+        let flat = self::map("#{ floors: [#{ data: [[0, 0, 1]] }] }");
+        assert!(MapGrid::new(&flat)
+            .neighbors(Cell::new(2, 0), MAX_STEP)
+            .is_empty());
+    }
+
+    #[test]
+    fn walls_block_edges() {
+        // the wall stands on x 1 between the cells of the row at z 1..2
+        let map = map(r#"
+            #{
+                floors: [#{ data: [[0, 0], [0, 0]] }],
+                walls: [#{ translation: [1.0, 0.0, 1.0], direction: "PX", size: [1.0, 2.0] }],
+            }
+        "#);
+        assert!(map.wall_between(Cell::new(0, 0), Cell::new(1, 0)));
+        assert!(!map.wall_between(Cell::new(0, 1), Cell::new(1, 1)));
+        assert!(!map.wall_between(Cell::new(0, 0), Cell::new(0, 1)));
+
+        let grid = MapGrid::new(&map);
+        assert_eq!(
+            grid.neighbors(Cell::new(0, 0), MAX_STEP),
+            vec![Cell::new(0, 1)]
+        );
+        let mut neighbors = grid.neighbors(Cell::new(0, 1), MAX_STEP);
+        neighbors.sort_by_key(|cell| (cell.i, cell.j));
+        assert_eq!(neighbors, vec![Cell::new(0, 0), Cell::new(1, 1)]);
+    }
+}
@@ -0,0 +1,115 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use super::{LevelId, Map, Visible};
+
+// a named trigger volume of a map, declared in `regions` of its script:
+//
+//     #{ name: "exit", tags: ["exit"], bounds: #{ min: [0.0, 0.0, 0.0], max: [1.0, 2.0, 3.0] } }
+//     #{ name: "hint", tags: ["tutorial"], cells: [[1, 1], [1, 2]] }
+//
+// a position is inside when it is in the box or above one of the `[x, z]`
+// cells of `Floor.data`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct Region {
+    name: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    bounds: Option<RegionBounds>,
+    #[serde(default)]
+    cells: Vec<[usize; 2]>,
+}
+
+// corners of an axis-aligned box in world space
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionBounds {
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Region {
+    fn contains(&self, map: &Map, world: Vec3) -> bool {
+        let in_bounds = self.bounds.as_ref().map_or(false, |bounds| {
+            world.cmpge(bounds.min).all() && world.cmple(bounds.max).all()
+        });
+        in_bounds
+            || map
+                .world_to_grid(world)
+                .map_or(false, |cell| self.cells.contains(&[cell.i, cell.j]))
+    }
+}
+
+// an entity whose position enters and leaves regions
+#[derive(Component, Default)]
+pub struct RegionTracker {
+    inside: Vec<RegionEntered>,
+}
+
+// sent when a `RegionTracker` entity moves into a region of a visible level
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionEntered {
+    pub entity: Entity,
+    pub level: LevelId,
+    pub region: String,
+    pub tags: Vec<String>,
+}
+
+// sent when it leaves the region again, or the level is despawned
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionExited {
+    pub entity: Entity,
+    pub level: LevelId,
+    pub region: String,
+    pub tags: Vec<String>,
+}
+
+pub(super) fn detect_regions(
+    levels: Query<(&LevelId, &Map, &Visible)>,
+    mut trackers: Query<(Entity, &GlobalTransform, &mut RegionTracker)>,
+    mut entered: EventWriter<RegionEntered>,
+    mut exited: EventWriter<RegionExited>,
+) {
+    for (entity, transform, mut tracker) in trackers.iter_mut() {
+        let position = transform.translation;
+        let mut inside = Vec::new();
+        for (level, map, visible) in levels.iter() {
+            if !visible.0 || !map.is_loaded() {
+                continue;
+            }
+            for region in map.regions.iter() {
+                if region.contains(map, position) {
+                    inside.push(RegionEntered {
+                        entity,
+                        level: level.clone(),
+                        region: region.name.clone(),
+                        tags: region.tags.clone(),
+                    });
+                }
+            }
+        }
+
+        let same =
+            |a: &RegionEntered, b: &RegionEntered| a.level == b.level && a.region == b.region;
+        for left in tracker.inside.iter() {
+            if !inside.iter().any(|region| same(region, left)) {
+                exited.send(RegionExited {
+                    entity,
+                    level: left.level.clone(),
+                    region: left.region.clone(),
+                    tags: left.tags.clone(),
+                });
+            }
+        }
+        for region in inside.iter() {
+            if !tracker.inside.iter().any(|inside| same(inside, region)) {
+                entered.send(region.clone());
+            }
+        }
+        if tracker.inside != inside {
+            tracker.inside = inside;
+        }
+    }
+}
@@ -0,0 +1,520 @@
+use bevy::{
+    asset::{AssetPath, HandleId},
+    prelude::*,
+};
+use rhai::{
+    module_resolvers::FileModuleResolver, Array, Dynamic, Engine, EvalAltResult, ModuleResolver,
+    NativeCallContext, Position, SharedModule, INT,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::MapAsset;
+
+// bounds on what a map script may do while it is evaluated, read once when
+// `MapPlugin` is built
+#[derive(Debug, Clone)]
+pub struct ScriptLimits {
+    pub max_operations: u64,
+    pub max_call_levels: usize,
+    pub max_string_size: usize,
+    pub max_array_size: usize,
+    pub max_map_size: usize,
+    // for the whole file, its map functions included
+    pub max_time: Duration,
+}
+
+impl Default for ScriptLimits {
+    fn default() -> Self {
+        Self {
+            max_operations: 5_000_000,
+            max_call_levels: 32,
+            max_string_size: 64 * 1024,
+            max_array_size: 64 * 1024,
+            max_map_size: 1024,
+            max_time: Duration::from_secs(2),
+        }
+    }
+}
+
+impl ScriptLimits {
+    // enforce the limits on an engine, the time limit counts from now
+    pub(super) fn apply(&self, engine: &mut Engine) {
+        let started = Instant::now();
+        self.apply_since(engine, move || started);
+    }
+
+    // for an engine kept between calls, the time limit counts from the last
+    // `ScriptClock::restart`
+    pub(super) fn apply_restartable(&self, engine: &mut Engine) -> ScriptClock {
+        let clock = ScriptClock(Arc::new(Mutex::new(Instant::now())));
+        let started = clock.clone();
+        self.apply_since(engine, move || *started.0.lock().unwrap());
+        clock
+    }
+
+    fn apply_since(
+        &self,
+        engine: &mut Engine,
+        started: impl Fn() -> Instant + Send + Sync + 'static,
+    ) {
+        engine
+            .set_max_operations(self.max_operations)
+            .set_max_call_levels(self.max_call_levels)
+            .set_max_string_size(self.max_string_size)
+            .set_max_array_size(self.max_array_size)
+            .set_max_map_size(self.max_map_size);
+        let max_time = self.max_time;
+        engine.on_progress(move |_| {
+            (started().elapsed() > max_time).then(|| {
+                let message = format!("ran longer than {:.1}s", max_time.as_secs_f32());
+                Dynamic::from(message)
+            })
+        });
+    }
+}
+
+// start of the time limit of an engine set up with `apply_restartable`
+#[derive(Clone)]
+pub(super) struct ScriptClock(Arc<Mutex<Instant>>);
+
+impl ScriptClock {
+    pub(super) fn restart(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+}
+
+// the modules every map file imported when it was last loaded, shared with
+// the loader. paths are on disk.
+#[derive(Default, Clone)]
+pub(super) struct ScriptImports(Arc<Mutex<HashMap<PathBuf, HashSet<PathBuf>>>>);
+
+impl ScriptImports {
+    // forget the imports of a map file before it is evaluated again
+    pub(super) fn clear(&self, map_file: &Path) {
+        self.0.lock().unwrap().remove(map_file);
+    }
+
+    fn insert(&self, map_file: &Path, module: PathBuf) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(map_file.to_path_buf())
+            .or_default()
+            .insert(module);
+    }
+
+    fn importers(&self) -> Vec<(PathBuf, Vec<PathBuf>)> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(map_file, modules)| (map_file.clone(), modules.iter().cloned().collect()))
+            .collect()
+    }
+}
+
+// resolves `import "rooms/corridor" as corridor;` to `scripts/rooms/corridor.rhai`
+// and records it as an import of the map file being loaded
+pub(super) struct ModuleTracker {
+    files: FileModuleResolver,
+    map_file: PathBuf,
+    imports: ScriptImports,
+}
+
+impl ModuleTracker {
+    pub(super) fn new(scripts: &Path, map_file: PathBuf, imports: ScriptImports) -> Self {
+        Self {
+            files: FileModuleResolver::new_with_path(scripts),
+            map_file,
+            imports,
+        }
+    }
+}
+
+impl ModuleResolver for ModuleTracker {
+    fn resolve(
+        &self,
+        engine: &Engine,
+        source: Option<&str>,
+        path: &str,
+        pos: Position,
+    ) -> Result<SharedModule, Box<EvalAltResult>> {
+        // tracked even when missing, creating the file reloads the map. the
+        // resolver has a base path, so nested imports resolve from `scripts/`
+        // as well and the parent of the importing module is only a fallback
+        let parent = source.and_then(|source| Path::new(source).parent());
+        let module = self.files.get_file_path(path, parent);
+        self.imports.insert(&self.map_file, module);
+        self.files.resolve(engine, source, path, pos)
+    }
+}
+
+// load every map file that imports a modified module again. the asset server
+// only loads a file anew once its asset was freed, so the levels of the file
+// keep weak handles until then and request the asset again afterwards.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn reload_importers(
+    mut commands: Commands,
+    time: Res<Time>,
+    imports: Res<ScriptImports>,
+    asset_server: Res<AssetServer>,
+    map_assets: Res<Assets<MapAsset>>,
+    levels: Query<(Entity, &Handle<MapAsset>)>,
+    mut elapsed: Local<f32>,
+    mut modified: Local<HashMap<PathBuf, Option<SystemTime>>>,
+    mut freeing: Local<Vec<(Entity, HandleId, AssetPath<'static>)>>,
+) {
+    freeing.retain(|(entity, handle, path)| {
+        if map_assets.get(*handle).is_some() {
+            return true;
+        }
+        if levels.get(*entity).is_ok() {
+            let map_asset: Handle<MapAsset> = asset_server.load(path.clone());
+            commands.entity(*entity).insert(map_asset);
+        }
+        false
+    });
+
+    *elapsed += time.delta_seconds();
+    if *elapsed < 0.5 {
+        return;
+    }
+    *elapsed = 0.0;
+
+    let importers = imports.importers();
+    let mut changed = HashSet::new();
+    for module in importers.iter().flat_map(|(_, modules)| modules) {
+        let time = std::fs::metadata(module)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        // the first look at a module only remembers its time
+        if let Some(last) = modified.insert(module.clone(), time) {
+            if last != time {
+                changed.insert(module.clone());
+            }
+        }
+    }
+
+    for (map_file, modules) in importers {
+        let module = match modules.iter().find(|module| changed.contains(*module)) {
+            Some(module) => module,
+            None => continue,
+        };
+        info!(
+            "{} changed, reloading {}",
+            module.display(),
+            map_file.display()
+        );
+        for (entity, handle) in levels.iter() {
+            let path = match asset_server.get_handle_path(handle) {
+                Some(path) => path,
+                None => continue,
+            };
+            let reloading = freeing.iter().any(|(freed, ..)| *freed == entity);
+            if map_file.ends_with(path.path()) && !reloading {
+                commands.entity(entity).insert(handle.clone_weak());
+                freeing.push((entity, handle.id, path.to_owned()));
+            }
+        }
+    }
+}
+
+// helpers for building `Floor.data` in map scripts, rows are `z` and columns `x`
+//
+// cells outside of `data` are skipped, so shapes can overlap the border.
+pub(super) fn register_helpers(engine: &mut Engine) {
+    engine
+        .register_fn("grid", grid)
+        .register_fn("fill_rect", fill_rect)
+        .register_fn("line", line)
+        .register_fn("circle", circle)
+        .register_fn("stamp", stamp)
+        .register_fn("mirror", mirror)
+        .register_fn("rotate", rotate);
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// `depth` rows of `width` cells set to `fill`
+fn grid(context: NativeCallContext, width: INT, depth: INT, fill: Dynamic) -> ScriptResult<Array> {
+    let (width, depth) = (width.max(0) as usize, depth.max(0) as usize);
+    let cells = width
+        .checked_mul(depth)
+        .ok_or_else(|| overflow("width * depth"))?;
+    check_size(&context, "grid", cells.max(width).max(depth))?;
+    let row: Array = vec![fill; width];
+    Ok(vec![Dynamic::from_array(row); depth])
+}
+
+fn fill_rect(
+    data: &mut Array,
+    x: INT,
+    z: INT,
+    width: INT,
+    depth: INT,
+    value: Dynamic,
+) -> ScriptResult<()> {
+    let z_end = z.checked_add(depth).ok_or_else(|| overflow("z + depth"))?;
+    let x_end = x.checked_add(width).ok_or_else(|| overflow("x + width"))?;
+    for j in clamp_range(z, z_end, data.len()) {
+        let row = row_len(data, j)?;
+        for i in clamp_range(x, x_end, row) {
+            set_cell(data, i, j, &value)?;
+        }
+    }
+    Ok(())
+}
+
+// cells from (x0, z0) to (x1, z1), both included
+fn line(
+    context: NativeCallContext,
+    data: &mut Array,
+    x0: INT,
+    z0: INT,
+    x1: INT,
+    z1: INT,
+    value: Dynamic,
+) -> ScriptResult<()> {
+    let span = |from: INT, to: INT, name| {
+        to.checked_sub(from)
+            .and_then(INT::checked_abs)
+            .ok_or_else(|| overflow(name))
+    };
+    let (dx, dz) = (span(x0, x1, "x1 - x0")?, -span(z0, z1, "z1 - z0")?);
+    check_size(&context, "line", dx.max(-dz) as usize)?;
+    let (step_x, step_z) = ((x1 - x0).signum(), (z1 - z0).signum());
+    let (mut x, mut z) = (x0, z0);
+    let mut error = dx.checked_add(dz).ok_or_else(|| overflow("error"))?;
+    loop {
+        set_cell(data, x, z, &value)?;
+        if x == x1 && z == z1 {
+            return Ok(());
+        }
+        let doubled = error.checked_mul(2).ok_or_else(|| overflow("2 * error"))?;
+        // `dx` and `dz` are only bounded when arrays have a size limit
+        if doubled >= dz {
+            error = error.checked_add(dz).ok_or_else(|| overflow("error"))?;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error = error.checked_add(dx).ok_or_else(|| overflow("error"))?;
+            z += step_z;
+        }
+    }
+}
+
+// filled disk around (x, z)
+fn circle(
+    context: NativeCallContext,
+    data: &mut Array,
+    x: INT,
+    z: INT,
+    radius: INT,
+    value: Dynamic,
+) -> ScriptResult<()> {
+    let radius = radius.max(0);
+    check_size(&context, "circle", radius as usize)?;
+    let squared = radius
+        .checked_mul(radius)
+        .ok_or_else(|| overflow("radius * radius"))?;
+    let (z_start, z_end) = (
+        z.saturating_sub(radius),
+        z.saturating_add(radius).saturating_add(1),
+    );
+    let (x_start, x_end) = (
+        x.saturating_sub(radius),
+        x.saturating_add(radius).saturating_add(1),
+    );
+    for j in clamp_range(z_start, z_end, data.len()) {
+        let row = row_len(data, j)?;
+        for i in clamp_range(x_start, x_end, row) {
+            // both are within `radius`, which squares without overflow
+            let (di, dj) = (i - x, j - z);
+            if di * di <= squared - dj * dj {
+                set_cell(data, i, j, &value)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// copy `pattern` with its first cell at (x, z), `()` cells are left out
+fn stamp(data: &mut Array, pattern: Array, x: INT, z: INT) -> ScriptResult<()> {
+    for (j, row) in rows(&pattern)?.into_iter().enumerate() {
+        for (i, cell) in row.into_iter().enumerate() {
+            if !cell.is_unit() {
+                let (x, z) = (x.checked_add(i as INT), z.checked_add(j as INT));
+                let (x, z) = x.zip(z).ok_or_else(|| overflow("stamp position"))?;
+                set_cell(data, x, z, &cell)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// a copy flipped along "x" (columns reversed) or "z" (rows reversed)
+fn mirror(data: Array, axis: &str) -> ScriptResult<Array> {
+    let mut rows = rows(&data)?;
+    match axis {
+        "x" => rows.iter_mut().for_each(|row| row.reverse()),
+        "z" => rows.reverse(),
+        _ => return Err(format!("mirror axis must be \"x\" or \"z\", not \"{}\"", axis).into()),
+    }
+    Ok(rows.into_iter().map(Dynamic::from_array).collect())
+}
+
+// a copy turned clockwise by quarter turns, seen from above
+fn rotate(data: Array, turns: INT) -> ScriptResult<Array> {
+    let mut rows = rows(&data)?;
+    for _ in 0..turns.rem_euclid(4) {
+        let width = rows.iter().map(Vec::len).max().unwrap_or(0);
+        rows = (0..width)
+            .map(|i| {
+                rows.iter()
+                    .rev()
+                    .map(|row| row.get(i).cloned().unwrap_or(Dynamic::UNIT))
+                    .collect()
+            })
+            .collect();
+    }
+    Ok(rows.into_iter().map(Dynamic::from_array).collect())
+}
+
+fn rows(data: &Array) -> ScriptResult<Vec<Array>> {
+    data.iter()
+        .enumerate()
+        .map(|(j, row)| {
+            row.as_array_ref()
+                .map(|row| row.clone())
+                .map_err(|_| format!("row {} is not an array", j).into())
+        })
+        .collect()
+}
+
+// an error for arguments bigger than arrays may be in the engine
+fn check_size(context: &NativeCallContext, name: &str, size: usize) -> ScriptResult<()> {
+    let max = context.engine().max_array_size();
+    if max > 0 && size > max {
+        return Err(format!(
+            "{} of size {} exceeds the array limit of {}",
+            name, size, max
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn overflow(what: &str) -> Box<EvalAltResult> {
+    format!("{} overflows", what).into()
+}
+
+// the part of `start..end` that indexes into `len` cells
+fn clamp_range(start: INT, end: INT, len: usize) -> Range<INT> {
+    let len = INT::try_from(len).unwrap_or(INT::MAX);
+    start.clamp(0, len)..end.clamp(0, len)
+}
+
+fn row_len(data: &Array, z: INT) -> ScriptResult<usize> {
+    data[z as usize]
+        .as_array_ref()
+        .map(|row| row.len())
+        .map_err(|_| format!("row {} is not an array", z).into())
+}
+
+fn set_cell(data: &mut Array, x: INT, z: INT, value: &Dynamic) -> ScriptResult<()> {
+    if x < 0 || z < 0 {
+        return Ok(());
+    }
+    let row = match data.get_mut(z as usize) {
+        Some(row) => row,
+        None => return Ok(()),
+    };
+    let mut row = row
+        .as_array_mut()
+        .map_err(|_| format!("row {} is not an array", z))?;
+    if let Some(cell) = row.get_mut(x as usize) {
+        *cell = value.clone();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rhai::serde::from_dynamic;
+    use serde::de::DeserializeOwned;
+
+    fn eval<T: DeserializeOwned>(script: &str) -> ScriptResult<T> {
+        let mut engine = Engine::new();
+        engine.set_max_array_size(ScriptLimits::default().max_array_size);
+        register_helpers(&mut engine);
+        from_dynamic(&engine.eval::<Dynamic>(script)?)
+    }
+
+    fn cells(script: &str) -> Vec<Vec<INT>> {
+        eval(script).unwrap()
+    }
+
+    #[test]
+    fn rotate_ragged() {
+        let rotated: Vec<Vec<Option<INT>>> = eval("rotate([[1, 2, 3], [4]], 1)").unwrap();
+        let expected = vec![
+            vec![Some(4), Some(1)],
+            vec![None, Some(2)],
+            vec![None, Some(3)],
+        ];
+        assert_eq!(rotated, expected);
+        assert_eq!(cells("rotate([[1, 2], [3, 4]], -1)"), [[2, 4], [1, 3]]);
+    }
+
+    #[test]
+    fn mirror_both_axes() {
+        assert_eq!(cells(r#"mirror([[1, 2], [3, 4]], "x")"#), [[2, 1], [4, 3]]);
+        assert_eq!(cells(r#"mirror([[1, 2], [3, 4]], "z")"#), [[3, 4], [1, 2]]);
+        assert!(eval::<Vec<Vec<INT>>>(r#"mirror([[1]], "y")"#).is_err());
+    }
+
+    #[test]
+    fn line_endpoints() {
+        let steep = cells("let data = grid(3, 5, 0); line(data, 0, 0, 2, 4, 1); data");
+        assert_eq!((steep[0][0], steep[4][2]), (1, 1));
+        // one cell per row
+        assert!(steep.iter().all(|row| row.iter().sum::<INT>() == 1));
+
+        let shallow = cells("let data = grid(5, 3, 0); line(data, 4, 2, 0, 0, 1); data");
+        assert_eq!((shallow[2][4], shallow[0][0]), (1, 1));
+        // one cell per column
+        for i in 0..5 {
+            assert_eq!(shallow.iter().map(|row| row[i]).sum::<INT>(), 1);
+        }
+    }
+
+    #[test]
+    fn shapes_clip_at_the_edges() {
+        let disk = cells("let data = grid(3, 3, 0); circle(data, 0, 0, 1, 1); data");
+        assert_eq!(disk, [[1, 1, 0], [1, 0, 0], [0, 0, 0]]);
+        let rect = cells("let data = grid(3, 2, 0); fill_rect(data, 1, -1, 5, 2, 7); data");
+        assert_eq!(rect, [[0, 7, 7], [0, 0, 0]]);
+        let stamped = cells("let data = grid(2, 2, 0); stamp(data, [[1, (), 1]], 1, 1); data");
+        assert_eq!(stamped, [[0, 0], [0, 1]]);
+    }
+
+    #[test]
+    fn oversized_arguments_fail() {
+        assert!(eval::<Vec<Vec<INT>>>("grid(70000, 1, 0)").is_err());
+        assert!(eval::<Vec<Vec<INT>>>("grid(300, 300, 0)").is_err());
+        let data = "let data = grid(1, 1, 0);";
+        let line = format!("{} line(data, 0, 0, 100000, 0, 1); data", data);
+        assert!(eval::<Vec<Vec<INT>>>(&line).is_err());
+        let circle = format!("{} circle(data, 0, 0, 100000, 1); data", data);
+        assert!(eval::<Vec<Vec<INT>>>(&circle).is_err());
+        let rect = format!("{} fill_rect(data, 2147483647, 0, 1, 1, 1); data", data);
+        assert!(eval::<Vec<Vec<INT>>>(&rect).is_err());
+    }
+}
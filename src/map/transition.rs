@@ -0,0 +1,256 @@
+use bevy::{asset::LoadState, prelude::*};
+use std::collections::VecDeque;
+
+use super::{
+    spawn_level, LevelId, LevelRegistry, Map, MapAsset, MapLoadError, MapLoadFailed, MapMaterials,
+    Tile, Visible,
+};
+
+// phases a level change goes through
+//
+// Idle/Active -> Despawning -> Unloading -> Loading -> Spawning -> Active
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LevelState {
+    Idle,
+    Loading,
+    Spawning,
+    Active,
+    Despawning,
+    Unloading,
+}
+
+// replace every visible level with the given one
+pub struct ChangeLevel(pub LevelId);
+
+// show the given level on top of the visible ones
+pub struct StackLevel(pub LevelId);
+
+// remove every visible level
+pub struct UnloadLevels;
+
+#[derive(Debug, Clone)]
+enum LevelRequest {
+    Change(LevelId),
+    Stack(LevelId),
+    Unload,
+}
+
+// bookkeeping of the level state machine
+#[derive(Debug, Default)]
+pub struct LevelTransition {
+    requests: VecDeque<LevelRequest>,
+    target: Option<LevelId>,
+    outgoing: Vec<LevelId>,
+    active: Vec<LevelId>,
+}
+
+impl LevelTransition {
+    // levels that are loaded and visible
+    pub fn active(&self) -> &[LevelId] {
+        &self.active
+    }
+
+    // level being loaded or spawned
+    pub fn target(&self) -> Option<&LevelId> {
+        self.target.as_ref()
+    }
+
+    // return to a stable state after the target could not be loaded
+    fn abort(&mut self, state: &mut State<LevelState>) {
+        self.target = None;
+        if self.active.is_empty() {
+            set_state(state, LevelState::Idle);
+        } else {
+            set_state(state, LevelState::Active);
+        }
+    }
+}
+
+// another system may have queued a state change this frame already, the
+// caller still runs in its state next frame and tries again
+fn set_state(state: &mut State<LevelState>, next: LevelState) {
+    if let Err(error) = state.set(next) {
+        warn!("level state change postponed: {:?}", error);
+    }
+}
+
+// requests are queued so they are handled one at a time in arrival order
+pub(super) fn queue_level_requests(
+    mut transition: ResMut<LevelTransition>,
+    mut changes: EventReader<ChangeLevel>,
+    mut stacks: EventReader<StackLevel>,
+    mut unloads: EventReader<UnloadLevels>,
+) {
+    for ChangeLevel(level) in changes.iter() {
+        let request = LevelRequest::Change(level.clone());
+        transition.requests.push_back(request);
+    }
+    for StackLevel(level) in stacks.iter() {
+        let request = LevelRequest::Stack(level.clone());
+        transition.requests.push_back(request);
+    }
+    for _ in unloads.iter() {
+        transition.requests.push_back(LevelRequest::Unload);
+    }
+}
+
+// runs while Idle or Active
+pub(super) fn start_transition(
+    mut state: ResMut<State<LevelState>>,
+    mut transition: ResMut<LevelTransition>,
+    registry: Res<LevelRegistry>,
+) {
+    let transition = &mut *transition;
+    let request = match transition.requests.pop_front() {
+        Some(request) => request,
+        None => return,
+    };
+    // dropped before anything is despawned, so the visible levels stay
+    if let LevelRequest::Change(level) | LevelRequest::Stack(level) = &request {
+        if registry.get(level).is_none() {
+            warn!("level `{}` is not registered", level);
+            return;
+        }
+    }
+    let (outgoing, target) = match &request {
+        LevelRequest::Change(level) => (transition.active.clone(), Some(level.clone())),
+        LevelRequest::Stack(level) => {
+            if transition.active.contains(level) {
+                return;
+            }
+            (Vec::new(), Some(level.clone()))
+        }
+        LevelRequest::Unload => (transition.active.clone(), None),
+    };
+    let next = if !outgoing.is_empty() {
+        LevelState::Despawning
+    } else if target.is_some() {
+        LevelState::Loading
+    } else {
+        return;
+    };
+    // handled next frame when another system changed the state first
+    if let Err(error) = state.set(next) {
+        warn!("level request postponed: {:?}", error);
+        transition.requests.push_front(request);
+        return;
+    }
+    transition.outgoing = outgoing;
+    transition.target = target;
+}
+
+pub(super) fn despawn_outgoing(
+    mut commands: Commands,
+    mut state: ResMut<State<LevelState>>,
+    transition: Res<LevelTransition>,
+    mut levels: Query<(&LevelId, &mut Visible)>,
+    tiles: Query<(Entity, &LevelId), With<Tile>>,
+) {
+    for (entity, level) in tiles.iter() {
+        if transition.outgoing.contains(level) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    for (level, mut visible) in levels.iter_mut() {
+        if transition.outgoing.contains(level) {
+            visible.0 = false;
+        }
+    }
+    set_state(&mut state, LevelState::Unloading);
+}
+
+pub(super) fn unload_outgoing(
+    mut state: ResMut<State<LevelState>>,
+    mut transition: ResMut<LevelTransition>,
+    mut levels: Query<(&LevelId, &mut Map)>,
+) {
+    let transition = &mut *transition;
+    for (level, mut map) in levels.iter_mut() {
+        if transition.outgoing.contains(level) {
+            map.clear();
+        }
+    }
+    let outgoing = std::mem::take(&mut transition.outgoing);
+    transition.active.retain(|level| !outgoing.contains(level));
+    if transition.target.is_some() {
+        set_state(&mut state, LevelState::Loading);
+    } else {
+        set_state(&mut state, LevelState::Idle);
+    }
+}
+
+// wait for the map asset of the target level, then copy it into the level
+#[allow(clippy::too_many_arguments)]
+pub(super) fn load_target(
+    mut state: ResMut<State<LevelState>>,
+    mut transition: ResMut<LevelTransition>,
+    asset_server: Res<AssetServer>,
+    registry: Res<LevelRegistry>,
+    map_assets: Res<Assets<MapAsset>>,
+    mut levels: Query<(&LevelId, &Handle<MapAsset>, &mut Map)>,
+    mut failures: EventWriter<MapLoadFailed>,
+) {
+    let target = match &transition.target {
+        Some(target) => target.clone(),
+        None => return transition.abort(&mut state),
+    };
+    if registry.get(&target).is_none() {
+        warn!("level `{}` is not registered", target);
+        return transition.abort(&mut state);
+    }
+    // the level entity is spawned once the registry is synced
+    let (_, handle, mut map) = match levels.iter_mut().find(|(level, ..)| **level == target) {
+        Some(level) => level,
+        None => return,
+    };
+    if let Some(map_asset) = map_assets.get(handle) {
+        *map = map_asset.map.clone();
+        set_state(&mut state, LevelState::Spawning);
+        return;
+    }
+    match asset_server.get_load_state(handle) {
+        // the script loaded but did not produce this level
+        LoadState::Loaded => {
+            let function = asset_server
+                .get_handle_path(handle)
+                .and_then(|path| path.label().map(str::to_string))
+                .unwrap_or_default();
+            let path = asset_server
+                .get_handle_path(handle)
+                .map(|path| path.path().display().to_string())
+                .unwrap_or_default();
+            let error = MapLoadError::MissingFunction { function };
+            error!("failed to load map `{}`: {}", target, error);
+            failures.send(MapLoadFailed {
+                level: target,
+                path,
+                error,
+            });
+            transition.abort(&mut state);
+        }
+        // reported by the loader
+        LoadState::Failed => transition.abort(&mut state),
+        _ => {}
+    }
+}
+
+pub(super) fn spawn_target(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    materials: Res<MapMaterials>,
+    mut state: ResMut<State<LevelState>>,
+    mut transition: ResMut<LevelTransition>,
+    mut levels: Query<(&LevelId, &Map, &mut Visible)>,
+) {
+    if let Some(target) = transition.target.take() {
+        for (level, map, mut visible) in levels.iter_mut() {
+            if *level != target || visible.0 || !map.is_loaded() {
+                continue;
+            }
+            spawn_level(&mut commands, &mut meshes, &materials, level, map);
+            visible.0 = true;
+        }
+        transition.active.push(target);
+    }
+    set_state(&mut state, LevelState::Active);
+}
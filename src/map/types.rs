@@ -0,0 +1,368 @@
+use bevy::prelude::*;
+use rhai::{
+    serde::{from_dynamic, to_dynamic},
+    Array, Dynamic, Engine, EvalAltResult, Module, FLOAT,
+};
+use serde::de::DeserializeOwned;
+
+use super::{Ambient, Direction, Floor, Map, Region, Stair, TilePalette, Wall};
+use crate::audio::{MapSfx, Playlist};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// `Map`, `Floor`, `Stair`, `Wall`, `Direction` and `Vec3` as native script
+// types, a map function may return a `Map` instead of an object map:
+//
+//     map()
+//         .with_position(vec3(1.0, 0.0, 1.0))
+//         .with_floor(floor(0, grid(3, 3, 0)))
+//         .with_stair(stair(vec3(1.0, 0.0, 0.0)).with_direction(Direction::PZ))
+//
+// the parts without a type of their own (palette, music, sfx, regions and
+// ambient sounds) take the object maps of the dynamic form. both forms mix,
+// an object map may hold native values and a `Map` object maps:
+//
+//     #{ floors: [floor(0, grid(3, 3, 0))], stairs: [#{ translation: vec3(1.0, 0.0, 0.0) }] }
+pub(super) fn register_types(engine: &mut Engine) {
+    register_vec3(engine);
+    register_direction(engine);
+    register_floor(engine);
+    register_stair(engine);
+    register_wall(engine);
+    register_map(engine);
+}
+
+fn register_vec3(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Vec3>("Vec3")
+        .register_fn("vec3", |x: FLOAT, y: FLOAT, z: FLOAT| Vec3::new(x, y, z))
+        .register_get_set("x", |v: &mut Vec3| v.x, |v: &mut Vec3, x: FLOAT| v.x = x)
+        .register_get_set("y", |v: &mut Vec3| v.y, |v: &mut Vec3, y: FLOAT| v.y = y)
+        .register_get_set("z", |v: &mut Vec3| v.z, |v: &mut Vec3, z: FLOAT| v.z = z)
+        .register_fn("+", |a: Vec3, b: Vec3| a + b)
+        .register_fn("-", |a: Vec3, b: Vec3| a - b)
+        .register_fn("*", |a: Vec3, scale: FLOAT| a * scale)
+        .register_fn("==", |a: Vec3, b: Vec3| a == b)
+        .register_fn("!=", |a: Vec3, b: Vec3| a != b)
+        .register_fn("to_string", |v: &mut Vec3| {
+            format!("vec3({}, {}, {})", v.x, v.y, v.z)
+        })
+        .register_fn("to_debug", |v: &mut Vec3| {
+            format!("vec3({}, {}, {})", v.x, v.y, v.z)
+        });
+}
+
+// `Direction::PX`, `Direction::MX`, `Direction::PZ` and `Direction::MZ`
+fn register_direction(engine: &mut Engine) {
+    let mut constants = Module::new();
+    for direction in [Direction::PX, Direction::MX, Direction::PZ, Direction::MZ] {
+        constants.set_var(format!("{:?}", direction), direction);
+    }
+    engine
+        .register_type_with_name::<Direction>("Direction")
+        .register_static_module("Direction", constants.into())
+        .register_fn("direction", |name: &str| {
+            parse::<Direction>(name.to_string().into())
+        })
+        .register_fn("==", |a: Direction, b: Direction| a == b)
+        .register_fn("!=", |a: Direction, b: Direction| a != b)
+        .register_fn("to_string", |direction: &mut Direction| {
+            format!("{:?}", direction)
+        })
+        .register_fn("to_debug", |direction: &mut Direction| {
+            format!("{:?}", direction)
+        });
+}
+
+fn register_floor(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Floor>("Floor")
+        .register_fn(
+            "floor",
+            |height: rhai::INT, data: Array| -> ScriptResult<Floor> {
+                let data = parse(Dynamic::from_array(data))?;
+                Ok(Floor { height, data })
+            },
+        )
+        .register_get_set(
+            "height",
+            |floor: &mut Floor| floor.height,
+            |floor: &mut Floor, height: rhai::INT| floor.height = height,
+        )
+        .register_get("data", |floor: &mut Floor| to_dynamic(&floor.data))
+        .register_set(
+            "data",
+            |floor: &mut Floor, data: Array| -> ScriptResult<()> {
+                floor.data = parse(Dynamic::from_array(data))?;
+                Ok(())
+            },
+        );
+}
+
+fn register_stair(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Stair>("Stair")
+        .register_fn("stair", |translation: Vec3| Stair {
+            translation,
+            direction: Direction::default(),
+            scale: Stair::default_scale(),
+        })
+        .register_get_set(
+            "translation",
+            |stair: &mut Stair| stair.translation,
+            |stair: &mut Stair, translation: Vec3| stair.translation = translation,
+        )
+        .register_get_set(
+            "direction",
+            |stair: &mut Stair| stair.direction.clone(),
+            |stair: &mut Stair, direction: Direction| stair.direction = direction,
+        )
+        .register_get_set(
+            "scale",
+            |stair: &mut Stair| stair.scale,
+            |stair: &mut Stair, scale: Vec3| stair.scale = scale,
+        )
+        .register_fn(
+            "with_direction",
+            |mut stair: Stair, direction: Direction| {
+                stair.direction = direction;
+                stair
+            },
+        )
+        .register_fn("with_scale", |mut stair: Stair, scale: Vec3| {
+            stair.scale = scale;
+            stair
+        });
+}
+
+// `size` is the `width` and `height` of the wall
+fn register_wall(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Wall>("Wall")
+        .register_fn("wall", |translation: Vec3, width: FLOAT, height: FLOAT| {
+            Wall {
+                translation,
+                direction: Direction::default(),
+                size: Vec2::new(width, height),
+            }
+        })
+        .register_get_set(
+            "translation",
+            |wall: &mut Wall| wall.translation,
+            |wall: &mut Wall, translation: Vec3| wall.translation = translation,
+        )
+        .register_get_set(
+            "direction",
+            |wall: &mut Wall| wall.direction.clone(),
+            |wall: &mut Wall, direction: Direction| wall.direction = direction,
+        )
+        .register_get_set(
+            "width",
+            |wall: &mut Wall| wall.size.x,
+            |wall: &mut Wall, width: FLOAT| wall.size.x = width,
+        )
+        .register_get_set(
+            "height",
+            |wall: &mut Wall| wall.size.y,
+            |wall: &mut Wall, height: FLOAT| wall.size.y = height,
+        )
+        .register_fn("with_direction", |mut wall: Wall, direction: Direction| {
+            wall.direction = direction;
+            wall
+        });
+}
+
+fn register_map(engine: &mut Engine) {
+    engine
+        .register_type_with_name::<Map>("Map")
+        .register_fn("map", Map::new)
+        .register_get_set(
+            "position",
+            |map: &mut Map| map.position,
+            |map: &mut Map, position: Vec3| map.position = position,
+        )
+        .register_get("floors", |map: &mut Map| to_array(&map.floors))
+        .register_set(
+            "floors",
+            |map: &mut Map, floors: Array| -> ScriptResult<()> {
+                map.floors = parse(Dynamic::from_array(floors))?;
+                Ok(())
+            },
+        )
+        .register_get("stairs", |map: &mut Map| to_array(&map.stairs))
+        .register_set(
+            "stairs",
+            |map: &mut Map, stairs: Array| -> ScriptResult<()> {
+                map.stairs = parse(Dynamic::from_array(stairs))?;
+                Ok(())
+            },
+        )
+        .register_get("walls", |map: &mut Map| to_array(&map.walls))
+        .register_set("walls", |map: &mut Map, walls: Array| -> ScriptResult<()> {
+            map.walls = parse(Dynamic::from_array(walls))?;
+            Ok(())
+        })
+        .register_fn("with_position", |mut map: Map, position: Vec3| {
+            map.position = position;
+            map
+        })
+        .register_fn("with_floor", |mut map: Map, floor: Floor| {
+            map.floors.push(floor);
+            map
+        })
+        .register_fn("with_stair", |mut map: Map, stair: Stair| {
+            map.stairs.push(stair);
+            map
+        })
+        .register_fn("with_wall", |mut map: Map, wall: Wall| {
+            map.walls.push(wall);
+            map
+        })
+        .register_fn(
+            "with_ambient",
+            |mut map: Map, ambient: rhai::Map| -> ScriptResult<Map> {
+                map.ambient.push(parse::<Ambient>(ambient.into())?);
+                Ok(map)
+            },
+        )
+        .register_fn(
+            "with_region",
+            |mut map: Map, region: rhai::Map| -> ScriptResult<Map> {
+                map.regions.push(parse::<Region>(region.into())?);
+                Ok(map)
+            },
+        )
+        .register_fn(
+            "with_palette",
+            |mut map: Map, palette: rhai::Map| -> ScriptResult<Map> {
+                map.palette = parse::<TilePalette>(palette.into())?;
+                Ok(map)
+            },
+        )
+        .register_fn(
+            "with_music",
+            |mut map: Map, music: rhai::Map| -> ScriptResult<Map> {
+                map.music = Some(parse::<Playlist>(music.into())?);
+                Ok(map)
+            },
+        )
+        .register_fn(
+            "with_sfx",
+            |mut map: Map, sfx: rhai::Map| -> ScriptResult<Map> {
+                map.sfx = parse::<MapSfx>(sfx.into())?;
+                Ok(map)
+            },
+        );
+}
+
+fn to_array<T: Clone + Send + Sync + 'static>(items: &[T]) -> Array {
+    items.iter().cloned().map(Dynamic::from).collect()
+}
+
+// the object map form of a part, with the same rules as a whole object map
+fn parse<T: DeserializeOwned>(value: Dynamic) -> ScriptResult<T> {
+    from_dynamic(&to_object_form(value))
+}
+
+// native values turned into the object maps, arrays and strings they stand
+// for, so serde reads a mix of both forms
+pub(super) fn to_object_form(value: Dynamic) -> Dynamic {
+    if value.is_array() {
+        let array = value.cast::<Array>();
+        return Dynamic::from_array(array.into_iter().map(to_object_form).collect());
+    }
+    if value.is_map() {
+        let mut map = value.cast::<rhai::Map>();
+        for value in map.values_mut() {
+            *value = to_object_form(std::mem::replace(value, Dynamic::UNIT));
+        }
+        return Dynamic::from_map(map);
+    }
+    if value.is::<Vec3>() {
+        return vec3_form(value.cast());
+    }
+    if value.is::<Direction>() {
+        return direction_form(&value.cast());
+    }
+    if value.is::<Floor>() {
+        let floor = value.cast::<Floor>();
+        let data = floor.data.iter().map(|row| {
+            let row = row.iter().map(|&id| Dynamic::from_int(id)).collect();
+            Dynamic::from_array(row)
+        });
+        let mut map = rhai::Map::new();
+        map.insert("height".into(), Dynamic::from_int(floor.height));
+        map.insert("data".into(), Dynamic::from_array(data.collect()));
+        return Dynamic::from_map(map);
+    }
+    if value.is::<Stair>() {
+        let stair = value.cast::<Stair>();
+        let mut map = rhai::Map::new();
+        map.insert("translation".into(), vec3_form(stair.translation));
+        map.insert("direction".into(), direction_form(&stair.direction));
+        map.insert("scale".into(), vec3_form(stair.scale));
+        return Dynamic::from_map(map);
+    }
+    if value.is::<Wall>() {
+        let wall = value.cast::<Wall>();
+        let size = vec![
+            Dynamic::from_float(wall.size.x),
+            Dynamic::from_float(wall.size.y),
+        ];
+        let mut map = rhai::Map::new();
+        map.insert("translation".into(), vec3_form(wall.translation));
+        map.insert("direction".into(), direction_form(&wall.direction));
+        map.insert("size".into(), Dynamic::from_array(size));
+        return Dynamic::from_map(map);
+    }
+    value
+}
+
+fn vec3_form(v: Vec3) -> Dynamic {
+    let xyz = [v.x, v.y, v.z];
+    Dynamic::from_array(xyz.into_iter().map(Dynamic::from_float).collect())
+}
+
+fn direction_form(direction: &Direction) -> Dynamic {
+    format!("{:?}", direction).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::map::MapAsset;
+
+    #[test]
+    fn native_values_in_object_maps() {
+        let script = r#"
+            #{
+                floors: [floor(0, grid(3, 2, 0))],
+                stairs: [stair(vec3(1.0, 0.0, 0.0)).with_direction(Direction::MX)],
+                walls: [#{
+                    translation: vec3(0.0, 0.0, 1.0),
+                    direction: Direction::PX,
+                    size: [2.0, 1.0],
+                }],
+            }
+        "#;
+        let map = MapAsset::from_script(script, None).unwrap().map;
+        assert_eq!((map.width(), map.depth()), (3, 2));
+        assert_eq!(map.stairs[0].translation.x, 1.0);
+        assert_eq!(map.walls[0].size.x, 2.0);
+    }
+
+    #[test]
+    fn setters_take_both_forms() {
+        let script = r#"
+            let level = map();
+            level.floors = [floor(0, grid(2, 2, 0)), #{ height: 1, data: grid(2, 2, -1) }];
+            level.stairs = [#{ translation: [1.0, 0.0, 0.0] }];
+            level.walls = [wall(vec3(0.0, 0.0, 0.0), 2.0, 1.0)];
+            level
+        "#;
+        let map = MapAsset::from_script(script, None).unwrap().map;
+        assert_eq!(map.floors.len(), 2);
+        assert_eq!(map.floors[1].height, 1);
+        assert_eq!(map.stairs.len(), 1);
+        assert_eq!(map.walls[0].size.y, 1.0);
+    }
+}
@@ -0,0 +1,265 @@
+use bevy::{ecs::system::SystemParam, prelude::*};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+use crate::map::{Cell, LevelId, MapAsset, MapGrid, MapQuery, MAX_STEP};
+
+pub struct PathfindingPlugin;
+
+impl Plugin for PathfindingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PathfindingSettings>()
+            .init_resource::<PathCache>()
+            .add_system(invalidate_paths);
+    }
+}
+
+pub struct PathfindingSettings {
+    // largest height difference a path climbs between two cells
+    pub max_step: f32,
+}
+
+impl Default for PathfindingSettings {
+    fn default() -> Self {
+        Self { max_step: MAX_STEP }
+    }
+}
+
+// paths found per level, cleared whenever the map of the level is reloaded.
+// each level keeps the `capacity` paths used last.
+pub struct PathCache {
+    capacity: usize,
+    levels: HashMap<LevelId, LevelPaths>,
+}
+
+#[derive(Default)]
+struct LevelPaths {
+    // path and the `clock` of its last use for each start and goal
+    paths: HashMap<(Cell, Cell), (Option<Vec<Cell>>, u64)>,
+    clock: u64,
+}
+
+impl Default for PathCache {
+    fn default() -> Self {
+        Self::with_capacity(256)
+    }
+}
+
+impl PathCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            levels: HashMap::new(),
+        }
+    }
+
+    pub fn get_or_find(
+        &mut self,
+        level: &LevelId,
+        grid: MapGrid,
+        start: Cell,
+        goal: Cell,
+        max_step: f32,
+    ) -> Option<Vec<Cell>> {
+        let capacity = self.capacity;
+        let level = self.levels.entry(level.clone()).or_default();
+        level.clock += 1;
+        let clock = level.clock;
+        if let Some((path, used)) = level.paths.get_mut(&(start, goal)) {
+            *used = clock;
+            return path.clone();
+        }
+
+        let path = find_path(grid, start, goal, max_step);
+        if level.paths.len() >= capacity {
+            let least_recent = level
+                .paths
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| *key);
+            if let Some(key) = least_recent {
+                level.paths.remove(&key);
+            }
+        }
+        level.paths.insert((start, goal), (path.clone(), clock));
+        path
+    }
+
+    pub fn invalidate(&mut self, level: &LevelId) {
+        self.levels.remove(level);
+    }
+}
+
+fn invalidate_paths(
+    mut cache: ResMut<PathCache>,
+    mut events: EventReader<AssetEvent<MapAsset>>,
+    levels: Query<(&LevelId, &Handle<MapAsset>)>,
+) {
+    for event in events.iter() {
+        let changed = match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => handle,
+            _ => continue,
+        };
+        for (level, handle) in levels.iter() {
+            if handle == changed {
+                cache.invalidate(level);
+            }
+        }
+    }
+}
+
+// cached pathfinding over the loaded levels
+#[derive(SystemParam)]
+pub struct Pathfinder<'w, 's> {
+    maps: MapQuery<'w, 's>,
+    cache: ResMut<'w, PathCache>,
+    settings: Res<'w, PathfindingSettings>,
+}
+
+impl<'w, 's> Pathfinder<'w, 's> {
+    pub fn find_path(&mut self, level: &LevelId, start: Cell, goal: Cell) -> Option<Vec<Cell>> {
+        let grid = self.maps.grid(level)?;
+        self.cache
+            .get_or_find(level, grid, start, goal, self.settings.max_step)
+    }
+}
+
+// shortest path from `start` to `goal`, both included, with A*
+//
+// cells are connected as in `MapGrid::neighbors`, so stairs link floors of
+// different heights and walls block the edge they stand on.
+pub fn find_path(grid: MapGrid, start: Cell, goal: Cell, max_step: f32) -> Option<Vec<Cell>> {
+    if !grid.is_walkable(start) || !grid.is_walkable(goal) {
+        return None;
+    }
+    let heuristic = |cell: Cell| cell.i.abs_diff(goal.i) + cell.j.abs_diff(goal.j);
+
+    let mut open = BinaryHeap::new();
+    let mut costs = HashMap::new();
+    let mut came_from = HashMap::new();
+    open.push(Reverse((heuristic(start), 0, start.i, start.j)));
+    costs.insert(start, 0);
+    while let Some(Reverse((_, cost, i, j))) = open.pop() {
+        let cell = Cell { i, j };
+        if cell == goal {
+            let mut path = vec![goal];
+            while let Some(&previous) = came_from.get(path.last().unwrap()) {
+                path.push(previous);
+            }
+            path.reverse();
+            return Some(path);
+        }
+        // an outdated entry, the cell was reached more cheaply since
+        if costs.get(&cell).map_or(false, |&best| cost > best) {
+            continue;
+        }
+        for next in grid.neighbors(cell, max_step) {
+            let next_cost = cost + 1;
+            if costs.get(&next).map_or(true, |&best| next_cost < best) {
+                costs.insert(next, next_cost);
+                came_from.insert(next, cell);
+                open.push(Reverse((
+                    next_cost + heuristic(next),
+                    next_cost,
+                    next.i,
+                    next.j,
+                )));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(script: &str, start: (usize, usize), goal: (usize, usize)) -> Option<Vec<Cell>> {
+        let asset = MapAsset::from_script(script, None).unwrap();
+        let (start, goal) = (Cell::new(start.0, start.1), Cell::new(goal.0, goal.1));
+        find_path(asset.grid(), start, goal, MAX_STEP)
+    }
+
+    #[test]
+    fn flat_corridor() {
+        let script = "#{ floors: [#{ data: [[0, 0, 0]] }] }";
+        let found = path(script, (0, 0), (2, 0)).unwrap();
+        let expected = vec![Cell::new(0, 0), Cell::new(1, 0), Cell::new(2, 0)];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn height_step_is_not_climbed() {
+        let script = "#{ floors: [#{ data: [[0, 1, 0]] }] }";
+        assert_eq!(path(script, (0, 0), (2, 0)), None);
+    }
+
+    #[test]
+    fn detour_around_raised_tile() {
+        let script = "#{ floors: [#{ data: [[0, 0, 0], [0, 1, 0], [0, 0, 0]] }] }";
+        let found = path(script, (0, 1), (2, 1)).unwrap();
+        assert_eq!(found.len(), 5);
+        assert!(!found.contains(&Cell::new(1, 1)));
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        // no tile between the two halves
+        let script = "#{ floors: [#{ data: [[0, -1, 0]] }] }";
+        assert_eq!(path(script, (0, 0), (2, 0)), None);
+        // the goal itself has no tile
+        assert_eq!(path(script, (0, 0), (1, 0)), None);
+    }
+
+    #[test]
+    fn stairs_link_heights() {
+        // cell 1 spans x 1..2 and ramps up towards cell 2 at x 0..1
+        let floors = "floors: [#{ data: [[0, 0, 1]] }]";
+        let stairs = r#"stairs: [#{ translation: [1.0, 0.0, 0.0], direction: "MX" }]"#;
+        let without = format!("#{{ {} }}", floors);
+        assert_eq!(path(&without, (0, 0), (2, 0)), None);
+        let with = format!("#{{ {}, {} }}", floors, stairs);
+        let found = path(&with, (0, 0), (2, 0)).unwrap();
+        let expected = vec![Cell::new(0, 0), Cell::new(1, 0), Cell::new(2, 0)];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn walls_block_their_edge() {
+        // the wall stands on x 1 between the cells of the row at z 1..2
+        let floors = "floors: [#{ data: [[0, 0], [0, 0]] }]";
+        let walls =
+            r#"walls: [#{ translation: [1.0, 0.0, 1.0], direction: "PX", size: [1.0, 2.0] }]"#;
+        let without = format!("#{{ {} }}", floors);
+        assert_eq!(path(&without, (0, 0), (1, 0)).unwrap().len(), 2);
+        let with = format!("#{{ {}, {} }}", floors, walls);
+        let found = path(&with, (0, 0), (1, 0)).unwrap();
+        let expected = vec![
+            Cell::new(0, 0),
+            Cell::new(0, 1),
+            Cell::new(1, 1),
+            Cell::new(1, 0),
+        ];
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn cache_keeps_most_recent_paths() {
+        let asset = MapAsset::from_script("#{ floors: [#{ data: [[0, 0, 0]] }] }", None).unwrap();
+        let level = LevelId("test".to_string());
+        let mut cache = PathCache::with_capacity(2);
+        let cells = [Cell::new(0, 0), Cell::new(1, 0), Cell::new(2, 0)];
+        cache.get_or_find(&level, asset.grid(), cells[0], cells[1], MAX_STEP);
+        cache.get_or_find(&level, asset.grid(), cells[0], cells[2], MAX_STEP);
+        // used again, so the next path replaces the other one
+        cache.get_or_find(&level, asset.grid(), cells[0], cells[1], MAX_STEP);
+        cache.get_or_find(&level, asset.grid(), cells[1], cells[2], MAX_STEP);
+
+        let paths = &cache.levels[&level].paths;
+        assert_eq!(paths.len(), 2);
+        assert!(paths.contains_key(&(cells[0], cells[1])));
+        assert!(!paths.contains_key(&(cells[0], cells[2])));
+    }
+}
@@ -0,0 +1,265 @@
+use bevy::prelude::*;
+use std::collections::HashMap;
+
+use crate::{
+    camera::MovableCamera,
+    controls::Action,
+    map::{Cell, LevelId, Map, MapGrid, MapQuery},
+};
+
+// the tile under the cursor, sent when it changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileHovered {
+    pub level: LevelId,
+    pub floor: usize,
+    pub cell: Cell,
+    pub world_pos: Vec3,
+}
+
+// the tile under the cursor when `Select` is pressed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileClicked {
+    pub level: LevelId,
+    pub floor: usize,
+    pub cell: Cell,
+    pub world_pos: Vec3,
+}
+
+// the tile currently under the cursor, only written when the tile changes so
+// `world_pos` is where the cursor entered it
+#[derive(Debug, Default)]
+pub struct HoveredTile(pub Option<TileHovered>);
+
+// the point under the cursor, updated on every frame
+#[derive(Debug, Default)]
+pub struct HoveredPosition(pub Option<Vec3>);
+
+// lowest and highest point of every level, measured again when its map changes
+#[derive(Default)]
+struct HeightRanges(HashMap<LevelId, Option<(f32, f32)>>);
+
+#[derive(Component)]
+struct TileHighlight;
+
+pub struct PickingPlugin;
+
+impl Plugin for PickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<HoveredTile>()
+            .init_resource::<HoveredPosition>()
+            .init_resource::<HeightRanges>()
+            .add_event::<TileHovered>()
+            .add_event::<TileClicked>()
+            .add_startup_system(setup_highlight)
+            .add_system(measure_heights.before(pick_tile))
+            .add_system(pick_tile)
+            .add_system(highlight_tile);
+    }
+}
+
+// a ray in world space
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    // the ray through a cursor position of a window, from the near to the far plane
+    pub fn from_cursor(
+        cursor: Vec2,
+        window: &Window,
+        camera: &Camera,
+        transform: &GlobalTransform,
+    ) -> Self {
+        let size = Vec2::new(window.width(), window.height());
+        let ndc = cursor / size * 2.0 - Vec2::ONE;
+        let ndc_to_world = transform.compute_matrix() * camera.projection_matrix.inverse();
+        // the depth range is reversed, 1 is the near plane
+        let near = ndc_to_world.project_point3(ndc.extend(1.0));
+        let far = ndc_to_world.project_point3(ndc.extend(0.0));
+        Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        }
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + self.direction * t
+    }
+
+    // point where the ray crosses the horizontal plane at `height`
+    pub fn plane_intersection(&self, height: f32) -> Option<Vec3> {
+        if self.direction.y == 0.0 {
+            return None;
+        }
+        let t = (height - self.origin.y) / self.direction.y;
+        (t >= 0.0).then(|| self.at(t))
+    }
+}
+
+// distance along the ray of the first point below the surface of the tiles
+// and the steps of the stairs of a map
+pub fn raycast(grid: MapGrid, ray: Ray) -> Option<f32> {
+    raycast_within(grid, ray, grid.height_range()?)
+}
+
+// `raycast` with the height range of the map already known
+pub fn raycast_within(grid: MapGrid, ray: Ray, (bottom, top): (f32, f32)) -> Option<f32> {
+    // the ray has to go down to hit anything
+    if ray.direction.y >= 0.0 {
+        return None;
+    }
+    let start = ((top - ray.origin.y) / ray.direction.y).max(0.0);
+    let end = (bottom - ray.origin.y) / ray.direction.y;
+    let below = |t: f32| {
+        let point = ray.at(t);
+        grid.geometry_height_at(point)
+            .map_or(false, |height| point.y <= height)
+    };
+
+    const STEP: f32 = 0.05;
+    let mut t = start;
+    while t <= end {
+        if below(t) {
+            // narrow down the crossing between the last two samples
+            let (mut above, mut under) = ((t - STEP).max(start), t);
+            for _ in 0..8 {
+                let middle = (above + under) / 2.0;
+                if below(middle) {
+                    under = middle;
+                } else {
+                    above = middle;
+                }
+            }
+            return Some(under);
+        }
+        t += STEP;
+    }
+    None
+}
+
+fn setup_highlight(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Plane { size: 1.0 })),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgba(1.0, 0.85, 0.2, 0.5),
+                alpha_mode: AlphaMode::Blend,
+                unlit: true,
+                ..default()
+            }),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(TileHighlight);
+}
+
+fn measure_heights(
+    maps: MapQuery,
+    changed: Query<&LevelId, Changed<Map>>,
+    mut ranges: ResMut<HeightRanges>,
+) {
+    for level in changed.iter() {
+        let range = maps.grid(level).and_then(|grid| grid.height_range());
+        ranges.0.insert(level.clone(), range);
+    }
+}
+
+// cast the cursor ray against every visible level and keep the nearest hit
+#[allow(clippy::too_many_arguments)]
+fn pick_tile(
+    windows: Res<Windows>,
+    actions: Res<Input<Action>>,
+    maps: MapQuery,
+    cameras: Query<(&Camera, &GlobalTransform), With<MovableCamera>>,
+    ranges: Res<HeightRanges>,
+    mut hovered: ResMut<HoveredTile>,
+    mut position: ResMut<HoveredPosition>,
+    mut hover_events: EventWriter<TileHovered>,
+    mut click_events: EventWriter<TileClicked>,
+) {
+    let window = match windows.get_primary() {
+        Some(window) => window,
+        None => return,
+    };
+    let (camera, transform) = match cameras.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let hit = window.cursor_position().and_then(|cursor| {
+        let ray = Ray::from_cursor(cursor, window, camera, transform);
+        maps.visible_grids()
+            .filter_map(|(level, grid)| {
+                let range = ranges.0.get(level).copied().flatten()?;
+                raycast_within(grid, ray, range).map(|t| (level, grid, t))
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .and_then(|(level, grid, t)| {
+                // step inside the tile, the hit may lie on a side face
+                let cell = grid.world_to_grid(ray.at(t + 0.001))?;
+                Some(TileHovered {
+                    level: level.clone(),
+                    floor: grid.top_floor(cell)?,
+                    cell,
+                    world_pos: ray.at(t),
+                })
+            })
+    });
+
+    let changed = match (&hovered.0, &hit) {
+        (Some(old), Some(new)) => {
+            (&old.level, old.floor, old.cell) != (&new.level, new.floor, new.cell)
+        }
+        (None, None) => false,
+        _ => true,
+    };
+    if changed {
+        if let Some(tile) = &hit {
+            hover_events.send(tile.clone());
+        }
+    }
+    if actions.just_pressed(Action::Select) {
+        if let Some(tile) = &hit {
+            click_events.send(TileClicked {
+                level: tile.level.clone(),
+                floor: tile.floor,
+                cell: tile.cell,
+                world_pos: tile.world_pos,
+            });
+        }
+    }
+    position.0 = hit.as_ref().map(|tile| tile.world_pos);
+    // keep `highlight_tile` from running while the cursor stays on the tile
+    if changed {
+        hovered.0 = hit;
+    }
+}
+
+// move the highlight onto the top of the hovered tile
+fn highlight_tile(
+    hovered: Res<HoveredTile>,
+    maps: MapQuery,
+    mut highlights: Query<(&mut Transform, &mut Visibility), With<TileHighlight>>,
+) {
+    if !hovered.is_changed() {
+        return;
+    }
+    for (mut transform, mut visibility) in highlights.iter_mut() {
+        let position = hovered
+            .0
+            .as_ref()
+            .and_then(|tile| maps.grid_to_world(&tile.level, tile.cell));
+        match position {
+            Some(position) => {
+                transform.translation = position + Vec3::Y * 0.01;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}